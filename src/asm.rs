@@ -0,0 +1,468 @@
+#![allow(clippy::pedantic)]
+
+use std::fmt;
+
+/// Largest value an A-instruction can load.
+pub const MAX_VALUE: u16 = 0x7FFF;
+
+/// A single line of Hack assembly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmItem {
+    AInstr(Address),
+    CInstr { dest: Dest, comp: Comp, jump: Jump },
+    Label(String),
+    Comment(String),
+}
+
+/// Operand of an A-instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Symbol(String),
+    Value(u16),
+}
+
+/// Destination of a C-instruction.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dest {
+    Null,
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+/// Computation of a C-instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+}
+
+/// Jump condition of a C-instruction.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Jump {
+    Null,
+    JGT,
+    JEQ,
+    JGE,
+    JLT,
+    JNE,
+    JLE,
+    JMP,
+}
+
+impl AsmItem {
+    /// `@symbol`
+    pub fn symbol(symbol: &str) -> Self {
+        AsmItem::AInstr(Address::Symbol(String::from(symbol)))
+    }
+
+    /// `@value`
+    ///
+    /// Panics if the value doesn't fit in an A-instruction.
+    pub fn value(value: i32) -> Self {
+        if value < 0 || value > MAX_VALUE as i32 {
+            panic!("{} can't be loaded by an A-instruction!", value);
+        }
+        AsmItem::AInstr(Address::Value(value as u16))
+    }
+
+    /// `dest=comp`
+    pub fn assign(dest: Dest, comp: Comp) -> Self {
+        AsmItem::CInstr {
+            dest,
+            comp,
+            jump: Jump::Null,
+        }
+    }
+
+    /// `comp;jump`
+    pub fn jump(comp: Comp, jump: Jump) -> Self {
+        AsmItem::CInstr {
+            dest: Dest::Null,
+            comp,
+            jump,
+        }
+    }
+
+    /// `(label)`
+    pub fn label(label: &str) -> Self {
+        AsmItem::Label(String::from(label))
+    }
+
+    /// `// comment`
+    pub fn comment(comment: &str) -> Self {
+        AsmItem::Comment(String::from(comment))
+    }
+}
+
+impl fmt::Display for AsmItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmItem::AInstr(Address::Symbol(symbol)) => write!(f, "@{symbol}"),
+            AsmItem::AInstr(Address::Value(value)) => write!(f, "@{value}"),
+            AsmItem::CInstr { dest, comp, jump } => {
+                if *dest != Dest::Null {
+                    write!(f, "{}=", dest.mnemonic())?;
+                }
+                write!(f, "{}", comp.mnemonic())?;
+                if *jump != Jump::Null {
+                    write!(f, ";{}", jump.mnemonic())?;
+                }
+                Ok(())
+            }
+            AsmItem::Label(label) => write!(f, "({label})"),
+            AsmItem::Comment(comment) => write!(f, "// {comment}"),
+        }
+    }
+}
+
+impl Dest {
+    pub const ALL: [Dest; 8] = [
+        Dest::Null,
+        Dest::M,
+        Dest::D,
+        Dest::MD,
+        Dest::A,
+        Dest::AM,
+        Dest::AD,
+        Dest::AMD,
+    ];
+
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Dest::ALL.into_iter().find(|dest| dest.bits() == bits)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Dest::ALL
+            .into_iter()
+            .find(|dest| dest.mnemonic() == mnemonic)
+    }
+
+    /// Does this write to RAM[A]?
+    pub fn writes_memory(&self) -> bool {
+        self.bits() & 0b001 != 0
+    }
+
+    /// The d1 d2 d3 bits of a C-instruction.
+    pub fn bits(&self) -> u16 {
+        match self {
+            Dest::Null => 0b000,
+            Dest::M => 0b001,
+            Dest::D => 0b010,
+            Dest::MD => 0b011,
+            Dest::A => 0b100,
+            Dest::AM => 0b101,
+            Dest::AD => 0b110,
+            Dest::AMD => 0b111,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Dest::Null => "",
+            Dest::M => "M",
+            Dest::D => "D",
+            Dest::MD => "MD",
+            Dest::A => "A",
+            Dest::AM => "AM",
+            Dest::AD => "AD",
+            Dest::AMD => "AMD",
+        }
+    }
+}
+
+impl Comp {
+    pub const ALL: [Comp; 28] = [
+        Comp::Zero,
+        Comp::One,
+        Comp::MinusOne,
+        Comp::D,
+        Comp::A,
+        Comp::M,
+        Comp::NotD,
+        Comp::NotA,
+        Comp::NotM,
+        Comp::NegD,
+        Comp::NegA,
+        Comp::NegM,
+        Comp::DPlusOne,
+        Comp::APlusOne,
+        Comp::MPlusOne,
+        Comp::DMinusOne,
+        Comp::AMinusOne,
+        Comp::MMinusOne,
+        Comp::DPlusA,
+        Comp::DPlusM,
+        Comp::DMinusA,
+        Comp::DMinusM,
+        Comp::AMinusD,
+        Comp::MMinusD,
+        Comp::DAndA,
+        Comp::DAndM,
+        Comp::DOrA,
+        Comp::DOrM,
+    ];
+
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Comp::ALL.into_iter().find(|comp| comp.bits() == bits)
+    }
+
+    /// Look up a computation, accepting operands of + & | in either order.
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        let swapped: String = match mnemonic.find(['+', '&', '|']) {
+            Some(i) if i > 0 => format!(
+                "{}{}{}",
+                &mnemonic[i + 1..],
+                &mnemonic[i..i + 1],
+                &mnemonic[..i]
+            ),
+            _ => String::from(mnemonic),
+        };
+        Comp::ALL
+            .into_iter()
+            .find(|comp| comp.mnemonic() == mnemonic || comp.mnemonic() == swapped)
+    }
+
+    /// Does this read RAM[A]?
+    pub fn reads_memory(&self) -> bool {
+        self.bits() & 0b1000000 != 0
+    }
+
+    /// The a c1 c2 c3 c4 c5 c6 bits of a C-instruction.
+    pub fn bits(&self) -> u16 {
+        match self {
+            Comp::Zero => 0b0101010,
+            Comp::One => 0b0111111,
+            Comp::MinusOne => 0b0111010,
+            Comp::D => 0b0001100,
+            Comp::A => 0b0110000,
+            Comp::M => 0b1110000,
+            Comp::NotD => 0b0001101,
+            Comp::NotA => 0b0110001,
+            Comp::NotM => 0b1110001,
+            Comp::NegD => 0b0001111,
+            Comp::NegA => 0b0110011,
+            Comp::NegM => 0b1110011,
+            Comp::DPlusOne => 0b0011111,
+            Comp::APlusOne => 0b0110111,
+            Comp::MPlusOne => 0b1110111,
+            Comp::DMinusOne => 0b0001110,
+            Comp::AMinusOne => 0b0110010,
+            Comp::MMinusOne => 0b1110010,
+            Comp::DPlusA => 0b0000010,
+            Comp::DPlusM => 0b1000010,
+            Comp::DMinusA => 0b0010011,
+            Comp::DMinusM => 0b1010011,
+            Comp::AMinusD => 0b0000111,
+            Comp::MMinusD => 0b1000111,
+            Comp::DAndA => 0b0000000,
+            Comp::DAndM => 0b1000000,
+            Comp::DOrA => 0b0010101,
+            Comp::DOrM => 0b1010101,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Comp::Zero => "0",
+            Comp::One => "1",
+            Comp::MinusOne => "-1",
+            Comp::D => "D",
+            Comp::A => "A",
+            Comp::M => "M",
+            Comp::NotD => "!D",
+            Comp::NotA => "!A",
+            Comp::NotM => "!M",
+            Comp::NegD => "-D",
+            Comp::NegA => "-A",
+            Comp::NegM => "-M",
+            Comp::DPlusOne => "D+1",
+            Comp::APlusOne => "A+1",
+            Comp::MPlusOne => "M+1",
+            Comp::DMinusOne => "D-1",
+            Comp::AMinusOne => "A-1",
+            Comp::MMinusOne => "M-1",
+            Comp::DPlusA => "D+A",
+            Comp::DPlusM => "D+M",
+            Comp::DMinusA => "D-A",
+            Comp::DMinusM => "D-M",
+            Comp::AMinusD => "A-D",
+            Comp::MMinusD => "M-D",
+            Comp::DAndA => "D&A",
+            Comp::DAndM => "D&M",
+            Comp::DOrA => "D|A",
+            Comp::DOrM => "D|M",
+        }
+    }
+}
+
+impl Jump {
+    pub const ALL: [Jump; 8] = [
+        Jump::Null,
+        Jump::JGT,
+        Jump::JEQ,
+        Jump::JGE,
+        Jump::JLT,
+        Jump::JNE,
+        Jump::JLE,
+        Jump::JMP,
+    ];
+
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Jump::ALL.into_iter().find(|jump| jump.bits() == bits)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        Jump::ALL
+            .into_iter()
+            .find(|jump| jump.mnemonic() == mnemonic)
+    }
+
+    /// The j1 j2 j3 bits of a C-instruction.
+    pub fn bits(&self) -> u16 {
+        match self {
+            Jump::Null => 0b000,
+            Jump::JGT => 0b001,
+            Jump::JEQ => 0b010,
+            Jump::JGE => 0b011,
+            Jump::JLT => 0b100,
+            Jump::JNE => 0b101,
+            Jump::JLE => 0b110,
+            Jump::JMP => 0b111,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Jump::Null => "",
+            Jump::JGT => "JGT",
+            Jump::JEQ => "JEQ",
+            Jump::JGE => "JGE",
+            Jump::JLT => "JLT",
+            Jump::JNE => "JNE",
+            Jump::JLE => "JLE",
+            Jump::JMP => "JMP",
+        }
+    }
+}
+
+/// Turn a list of assembly items into Hack assembly text, one item per line.
+///
+/// A blank line is left before each comment that isn't directly preceded by
+/// another comment, which keeps each VM command's code visually grouped.
+pub fn serialize(items: &[AsmItem]) -> String {
+    let mut write_string = String::new();
+    let mut previous_was_comment = true;
+    for item in items {
+        let is_comment = matches!(item, AsmItem::Comment(_));
+        if is_comment && !previous_was_comment {
+            write_string.push('\n');
+        }
+        write_string.push_str(item.to_string().as_str());
+        write_string.push('\n');
+        previous_was_comment = is_comment;
+    }
+    write_string
+}
+
+/// Parse Hack assembly text into assembly items.
+///
+/// Whole-line comments are kept, while comments after an instruction are dropped.
+/// Panics on anything that isn't valid Hack assembly, naming the line.
+pub fn parse(text: &str) -> Vec<AsmItem> {
+    let mut items = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(comment) = line.strip_prefix("//") {
+            items.push(AsmItem::comment(comment.trim()));
+            continue;
+        }
+        let line: String = line
+            .split("//")
+            .next()
+            .unwrap()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        if line.is_empty() {
+            continue;
+        }
+        let item = if let Some(label) = line.strip_prefix('(') {
+            match label.strip_suffix(')') {
+                Some(label) => AsmItem::label(label),
+                None => panic!("Unclosed label on line {}: {:?}", number + 1, line),
+            }
+        } else if let Some(address) = line.strip_prefix('@') {
+            match address.parse::<i32>() {
+                Ok(value) => AsmItem::value(value),
+                Err(_) => AsmItem::symbol(address),
+            }
+        } else {
+            let (dest, rest) = match line.split_once('=') {
+                Some((dest, rest)) => (dest, rest),
+                None => ("", line.as_str()),
+            };
+            let (comp, jump) = match rest.split_once(';') {
+                Some((comp, jump)) => (comp, jump),
+                None => (rest, ""),
+            };
+            match (
+                Dest::from_mnemonic(dest),
+                Comp::from_mnemonic(comp),
+                Jump::from_mnemonic(jump),
+            ) {
+                (Some(dest), Some(comp), Some(jump)) => AsmItem::CInstr { dest, comp, jump },
+                _ => panic!("Invalid instruction on line {}: {:?}", number + 1, line),
+            }
+        };
+        items.push(item);
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_serializes() {
+        let text = "// push constant 7\n@7\nD=A\n(Main.main$LOOP)\n@SP\nAM=M+1\nD;JGT\n";
+        let items = parse(text);
+        assert_eq!(items.len(), 7);
+        assert_eq!(serialize(&items), text);
+        // Comments after an instruction and spaces inside one are dropped
+        assert_eq!(parse("D = D + 1 // inc\n"), parse("D=D+1\n"));
+    }
+}
@@ -0,0 +1,95 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::{Address, AsmItem};
+use std::collections::HashMap;
+
+/// First RAM address handed out to variables.
+const FIRST_VARIABLE: u16 = 16;
+
+/// Hack's predefined symbols and their addresses.
+pub fn predefined_symbols() -> Vec<(String, u16)> {
+    let mut symbols = vec![
+        (String::from("SP"), 0),
+        (String::from("LCL"), 1),
+        (String::from("ARG"), 2),
+        (String::from("THIS"), 3),
+        (String::from("THAT"), 4),
+        (String::from("SCREEN"), 16384),
+        (String::from("KBD"), 24576),
+    ];
+    for i in 0..16 {
+        symbols.push((format!("R{i}"), i));
+    }
+    symbols
+}
+
+/// Maps labels and variables to addresses, following the Hack assembler rules.
+pub struct SymbolTable {
+    symbols: HashMap<String, u16>,
+    next_variable: u16,
+    /// Variables in the order they were given addresses, from FIRST_VARIABLE up.
+    variables: Vec<String>,
+}
+
+impl SymbolTable {
+    /// Build the table with the predefined symbols and every label in `items`.
+    pub fn new(items: &[AsmItem]) -> Self {
+        let mut symbols: HashMap<String, u16> = predefined_symbols().into_iter().collect();
+        let mut rom_address: u16 = 0;
+        for item in items {
+            match item {
+                AsmItem::Label(label) => {
+                    symbols.insert(label.clone(), rom_address);
+                }
+                AsmItem::AInstr(_) | AsmItem::CInstr { .. } => rom_address += 1,
+                AsmItem::Comment(_) => {}
+            }
+        }
+        SymbolTable {
+            symbols,
+            next_variable: FIRST_VARIABLE,
+            variables: Vec::new(),
+        }
+    }
+
+    /// Look up a symbol, allocating the next free RAM address if it's a new variable.
+    pub fn address_of(&mut self, symbol: &str) -> u16 {
+        if let Some(address) = self.symbols.get(symbol) {
+            return *address;
+        }
+        let address = self.next_variable;
+        self.symbols.insert(String::from(symbol), address);
+        self.variables.push(String::from(symbol));
+        self.next_variable += 1;
+        address
+    }
+
+    /// The variable given a RAM address, if any.
+    pub fn variable_at(&self, address: u16) -> Option<&str> {
+        let index = address.checked_sub(FIRST_VARIABLE)?;
+        self.variables.get(index as usize).map(String::as_str)
+    }
+}
+
+/// Encode one instruction as a 16-bit Hack word.
+///
+/// Returns None for labels and comments, which don't take up ROM.
+pub fn encode(item: &AsmItem, symbols: &mut SymbolTable) -> Option<u16> {
+    match item {
+        AsmItem::AInstr(Address::Value(value)) => Some(*value),
+        AsmItem::AInstr(Address::Symbol(symbol)) => Some(symbols.address_of(symbol)),
+        AsmItem::CInstr { dest, comp, jump } => {
+            Some(0b111 << 13 | comp.bits() << 6 | dest.bits() << 3 | jump.bits())
+        }
+        AsmItem::Label(_) | AsmItem::Comment(_) => None,
+    }
+}
+
+/// Assemble a program into Hack machine code.
+pub fn assemble(items: &[AsmItem]) -> Vec<u16> {
+    let mut symbols = SymbolTable::new(items);
+    items
+        .iter()
+        .filter_map(|item| encode(item, &mut symbols))
+        .collect()
+}
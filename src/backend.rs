@@ -0,0 +1,83 @@
+#![allow(clippy::pedantic)]
+
+use crate::emulator::RunOptions;
+use crate::listing::Origin;
+use crate::parser::CommandType;
+
+/// What the VM code gets translated into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Target {
+    /// Hack assembly for the Nand2Tetris CPU emulator
+    Hack,
+    /// Portable C that can be compiled and run natively
+    C,
+    /// A WebAssembly text module for running in the browser
+    Wat,
+    /// x86-64 GNU assembly for Linux
+    #[value(name = "x86_64")]
+    X86_64,
+}
+
+/// A translation target, with one method per VM command.
+///
+/// The dispatcher in main calls these in program order: the prologue, then
+/// every command of every file, then the epilogue. Backends collect their
+/// output and write it all out at the end.
+pub trait Backend {
+    /// Start code for the whole program.
+    ///
+    /// `init_function` is the function to bootstrap into, if any.
+    fn write_prologue(&mut self, init_function: Option<&str>);
+
+    /// End code for the whole program.
+    fn write_epilogue(&mut self);
+
+    /// Start translating a new .vm file.
+    fn set_filename(&mut self, filename: &str);
+
+    /// Called before each command with where it came from in the source.
+    fn set_origin(&mut self, _origin: &Origin) {}
+
+    fn write_arithmetic(&mut self, op: &str);
+
+    fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: i16);
+
+    fn write_label(&mut self, label: &str);
+
+    fn write_goto(&mut self, label: &str);
+
+    fn write_if(&mut self, label: &str);
+
+    fn write_function(&mut self, function_name: &str, n_vars: i16);
+
+    fn write_call(&mut self, function_name: &str, n_args: i16);
+
+    /// Write a call that's immediately followed by `return`.
+    ///
+    /// Backends that can't reuse the caller's frame fall back on a plain call.
+    fn write_tail_call(&mut self, function_name: &str, n_args: i16) {
+        self.write_call(function_name, n_args);
+        self.write_return();
+    }
+
+    fn write_return(&mut self);
+
+    /// Write a block of hand-written Hack assembly.
+    ///
+    /// Only Hack can run it, so other backends refuse. translate() reports asm
+    /// blocks for other targets before they get here.
+    fn write_asm(&mut self, _lines: &[String]) {
+        panic!("asm blocks can only be translated to Hack assembly!");
+    }
+
+    /// Write the translated program, adding the target's extension to `out_path`.
+    fn write_output(&self, out_path: &str);
+
+    /// Run the translated program in the emulator, returning false if the sanitizer found problems.
+    ///
+    /// Only Hack has an emulator, so other backends refuse. check_usage() makes
+    /// --run a usage error for other targets, so this isn't reached.
+    fn run(&self, _options: &RunOptions) -> bool {
+        panic!("--run only works with --target hack");
+    }
+}
@@ -0,0 +1,325 @@
+#![allow(clippy::pedantic)]
+
+use crate::backend::Backend;
+use crate::listing::Origin;
+use crate::parser::CommandType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use indoc::formatdoc;
+
+/// RAM address of the first static variable, as handed out by the Hack assembler.
+const FIRST_STATIC: u16 = 16;
+
+/// Translates to a self-contained C program that models Hack RAM.
+///
+/// Everything runs inside a single `run()` function, with each VM function
+/// and label becoming a C label. Return addresses are small integers pushed
+/// on the VM stack like on Hack, and `return` jumps back through a switch.
+pub struct CWriter {
+    filename: String,
+    current_function: String,
+    code: String,
+    call_counter: u16,
+    static_lookup: HashMap<String, u16>,
+    /// The label written by the last command, for spotting `label X; goto X`.
+    previous_label: Option<String>,
+}
+
+impl CWriter {
+    pub fn new() -> Self {
+        CWriter {
+            filename: String::from("Sys"),
+            current_function: String::from("bootstrap"),
+            code: String::new(),
+            call_counter: 0,
+            static_lookup: HashMap::new(),
+            previous_label: None,
+        }
+    }
+
+    /// Labels are scoped to the file and function they're declared in.
+    fn scoped_label(&self, label: &str) -> String {
+        c_label(
+            "L",
+            format!("{}.{}${label}", self.filename, self.current_function).as_str(),
+        )
+    }
+
+    /// Statics get consecutive RAM addresses in the order they're first used.
+    fn static_address(&mut self, index: i16) -> u16 {
+        let next = FIRST_STATIC + self.static_lookup.len() as u16;
+        *self
+            .static_lookup
+            .entry(format!("{}.{index}", self.filename))
+            .or_insert(next)
+    }
+
+    /// C expression for the RAM cell of a memory segment.
+    fn segment_cell(&mut self, segment: &str, index: i16) -> String {
+        match segment {
+            "local" => format!("ram[ADDR(LCL, {index})]"),
+            "argument" => format!("ram[ADDR(ARG, {index})]"),
+            "this" => format!("ram[ADDR(THIS, {index})]"),
+            "that" => format!("ram[ADDR(THAT, {index})]"),
+            "pointer" => match index {
+                0 => String::from("THIS"),
+                1 => String::from("THAT"),
+                _ => panic!("pointer can only be 0 or 1!"),
+            },
+            "temp" => format!("ram[{}]", index + 5),
+            "static" => format!("ram[{}]", self.static_address(index)),
+            _ => panic!("Unknown segment {:?}!", segment),
+        }
+    }
+
+    fn push_line(&mut self, line: &str) {
+        self.code.push_str("    ");
+        self.code.push_str(line);
+        self.code.push('\n');
+    }
+}
+
+impl Backend for CWriter {
+    fn write_prologue(&mut self, init_function: Option<&str>) {
+        if let Some(init_function) = init_function {
+            self.push_line("/* bootstrap */");
+            self.push_line("SP = 256;");
+            self.write_call(init_function, 0);
+            // Returning from the entry point ends the program
+            self.push_line("goto halt;");
+        }
+    }
+
+    fn write_epilogue(&mut self) {
+        self.push_line("goto halt;");
+    }
+
+    fn set_filename(&mut self, filename: &str) {
+        self.filename = String::from(filename);
+        self.current_function = String::from("bootstrap");
+    }
+
+    fn set_origin(&mut self, origin: &Origin) {
+        let comment = format!("/* {}:{}: {} */", origin.file, origin.line, origin.command);
+        self.push_line(comment.as_str());
+    }
+
+    fn write_arithmetic(&mut self, op: &str) {
+        self.previous_label = None;
+        let line = match op {
+            "add" => "BINARY(x + y);",
+            "sub" => "BINARY(x - y);",
+            "and" => "BINARY(x & y);",
+            "or" => "BINARY(x | y);",
+            "neg" => "UNARY(-x);",
+            "not" => "UNARY(~x);",
+            // Like on Hack, comparisons look at the wrapped difference
+            "eq" => "BINARY(wrap(x - y) == 0 ? -1 : 0);",
+            "gt" => "BINARY(wrap(x - y) > 0 ? -1 : 0);",
+            "lt" => "BINARY(wrap(x - y) < 0 ? -1 : 0);",
+            // Dividing by zero gives 0, leaving x as the remainder
+            "mul" => "BINARY(x * y);",
+            "div" => "BINARY(y == 0 ? 0 : x / y);",
+            "mod" => "BINARY(y == 0 ? x : x % y);",
+            // Shifting by anything outside 0..=15 gives 0
+            "shl" => "BINARY((uint16_t)y > 15 ? 0 : (uint16_t)x << y);",
+            "shr" => "BINARY((uint16_t)y > 15 ? 0 : (uint16_t)x >> y);",
+            _ => panic!("Tried to do math on a not math ({:?}) thing!", op),
+        };
+        self.push_line(line);
+    }
+
+    fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: i16) {
+        self.previous_label = None;
+        let line = match command_type {
+            CommandType::PUSH if segment == "constant" => format!("push({index});"),
+            CommandType::PUSH => format!("push({});", self.segment_cell(segment, index)),
+            CommandType::POP if segment == "constant" => panic!("Can't pop constant!"),
+            CommandType::POP => format!("{} = pop();", self.segment_cell(segment, index)),
+            _ => panic!("Error in matching what command to run in push_pop!"),
+        };
+        self.push_line(line.as_str());
+    }
+
+    fn write_label(&mut self, label: &str) {
+        let line = format!("{}:;", self.scoped_label(label));
+        self.push_line(line.as_str());
+        self.previous_label = Some(String::from(label));
+    }
+
+    fn write_goto(&mut self, label: &str) {
+        // `label X; goto X` spins forever, which is how Hack programs halt
+        let line = if self.previous_label.as_deref() == Some(label) {
+            String::from("goto halt;")
+        } else {
+            format!("goto {};", self.scoped_label(label))
+        };
+        self.push_line(line.as_str());
+        self.previous_label = None;
+    }
+
+    fn write_if(&mut self, label: &str) {
+        self.previous_label = None;
+        let line = format!("if (pop() != 0) goto {};", self.scoped_label(label));
+        self.push_line(line.as_str());
+    }
+
+    fn write_function(&mut self, function_name: &str, n_vars: i16) {
+        self.previous_label = None;
+        let line = format!("{}:;", c_label("F", function_name));
+        self.push_line(line.as_str());
+        for _ in 0..n_vars {
+            self.push_line("push(0);");
+        }
+        self.current_function = String::from(function_name.split('.').nth(1).unwrap());
+    }
+
+    fn write_call(&mut self, function_name: &str, n_args: i16) {
+        self.previous_label = None;
+        let return_id = self.call_counter;
+        self.call_counter += 1;
+        let call = formatdoc! {"
+            push({return_id});
+                push(LCL);
+                push(ARG);
+                push(THIS);
+                push(THAT);
+                ARG = wrap(SP - 5 - {n_args});
+                LCL = SP;
+                goto {};
+                R{return_id}:;",
+            c_label("F", function_name)
+        };
+        self.push_line(call.as_str());
+    }
+
+    fn write_return(&mut self) {
+        self.previous_label = None;
+        self.push_line(
+            formatdoc! {"
+            {{
+                    int16_t frame = LCL;
+                    ret = ram[ADDR(frame, -5)];
+                    ram[ADDR(ARG, 0)] = pop();
+                    SP = wrap(ARG + 1);
+                    THAT = ram[ADDR(frame, -1)];
+                    THIS = ram[ADDR(frame, -2)];
+                    ARG = ram[ADDR(frame, -3)];
+                    LCL = ram[ADDR(frame, -4)];
+                    goto dispatch;
+                }}"
+            }
+            .as_str(),
+        );
+    }
+
+    fn write_output(&self, out_path: &str) {
+        let mut dispatch = String::new();
+        for return_id in 0..self.call_counter {
+            dispatch.push_str(format!("    case {return_id}: goto R{return_id};\n").as_str());
+        }
+        let program = formatdoc! {r#"
+            /* Generated by vm-translator-rust. Compile with: cc -O2 -o program program.c
+             *
+             * Run as `./program [address=value ...] [address ...]` to set RAM before
+             * the program starts and print RAM once it halts. */
+            #include <stdint.h>
+            #include <stdio.h>
+            #include <stdlib.h>
+            #include <string.h>
+
+            int16_t ram[32768];
+
+            #define SP ram[0]
+            #define LCL ram[1]
+            #define ARG ram[2]
+            #define THIS ram[3]
+            #define THAT ram[4]
+
+            /* Hack arithmetic is 16-bit two's complement */
+            static int16_t wrap(int32_t value) {{
+                return (int16_t)(uint16_t)(uint32_t)value;
+            }}
+
+            #define ADDR(base, offset) ((uint16_t)wrap((base) + (offset)) & 0x7FFF)
+
+            static void push(int16_t value) {{
+                ram[ADDR(SP, 0)] = value;
+                SP = wrap(SP + 1);
+            }}
+
+            static int16_t pop(void) {{
+                SP = wrap(SP - 1);
+                return ram[ADDR(SP, 0)];
+            }}
+
+            #define UNARY(expr) do {{ int16_t x = pop(); push(wrap(expr)); }} while (0)
+            #define BINARY(expr) do {{ int16_t y = pop(); int16_t x = pop(); push(wrap(expr)); }} while (0)
+
+            static void run(void) {{
+                int16_t ret = 0;
+            {}
+            dispatch:
+                switch (ret) {{
+            {}    }}
+            halt:
+                return;
+            }}
+
+            int main(int argc, char **argv) {{
+                for (int i = 1; i < argc; i++) {{
+                    char *value = strchr(argv[i], '=');
+                    if (value != NULL) {{
+                        ram[atoi(argv[i]) & 0x7FFF] = (int16_t)atoi(value + 1);
+                    }}
+                }}
+                run();
+                for (int i = 1; i < argc; i++) {{
+                    if (strchr(argv[i], '=') == NULL) {{
+                        int address = atoi(argv[i]) & 0x7FFF;
+                        printf("RAM[%d] = %d\n", address, ram[address]);
+                    }}
+                }}
+                return 0;
+            }}
+            "#,
+            self.code, dispatch
+        };
+        let mut out_file =
+            File::create(format!("{out_path}.c")).expect("Unable to create new file");
+        out_file
+            .write_all(program.as_bytes())
+            .expect("Error writing to file");
+    }
+}
+
+/// Turn a VM name into a C label, escaping anything that isn't alphanumeric.
+///
+/// `_` becomes `__` and other characters become `_` plus their hex code, so
+/// distinct VM names always give distinct C labels.
+pub fn c_label(prefix: &str, name: &str) -> String {
+    let mut label = format!("{prefix}_");
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c);
+        } else if c == '_' {
+            label.push_str("__");
+        } else {
+            label.push_str(format!("_{:02x}", c as u32).as_str());
+        }
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_label_escapes_names() {
+        assert_eq!(c_label("F", "Main.main"), "F_Main_2emain");
+        assert_eq!(c_label("L", "a_b$c"), "L_a__b_24c");
+    }
+}
@@ -0,0 +1,292 @@
+#![allow(clippy::pedantic)]
+
+use crate::os;
+use crate::parser::{CommandType, Parser};
+use crate::preprocessor::SourceMap;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How to write out a call graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT, for `dot -Tsvg`
+    Dot,
+    /// A JSON object with the functions, calls and cycles
+    Json,
+}
+
+/// Which functions call which, across all the files of a program.
+pub struct CallGraph {
+    /// Where each function is defined, as file and line.
+    functions: BTreeMap<String, (String, u16)>,
+    /// How many places each function calls each other function from.
+    calls: BTreeMap<(String, String), usize>,
+    /// The functions each function can end up calling, directly or not.
+    reaches: BTreeMap<String, BTreeSet<String>>,
+    /// The function the program starts at, if it has one.
+    entry: Option<String>,
+}
+
+/// What a name that's called or defined turns out to be.
+#[derive(PartialEq)]
+enum Kind {
+    Defined,
+    /// Defined by the bundled OS, which is linked in when translating.
+    Os,
+    Undefined,
+}
+
+impl CallGraph {
+    /// Build the graph from the `function` and `call` commands in the sources.
+    ///
+    /// The program starts at Sys.init if it defines one, or else at Main.main
+    /// as the OS's Sys.init calls it. With neither, nothing counts as
+    /// unreachable. Calls outside any function are left out.
+    pub fn build(sources: &[(String, SourceMap)]) -> Self {
+        let mut functions = BTreeMap::new();
+        let mut calls = BTreeMap::new();
+        for (contents, map) in sources {
+            let mut p = Parser::new(contents, true);
+            let mut caller: Option<String> = None;
+            while p.has_more_lines() {
+                p.advance();
+                match p.command_type() {
+                    CommandType::FUNCTION => {
+                        let name = String::from(p.arg1().unwrap());
+                        let (file, line) = map.locate(p.current_line);
+                        functions.insert(name.clone(), (String::from(file), line));
+                        caller = Some(name);
+                    }
+                    CommandType::CALL => {
+                        if let Some(caller) = &caller {
+                            *calls
+                                .entry((caller.clone(), String::from(p.arg1().unwrap())))
+                                .or_insert(0) += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut callees: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (from, to) in calls.keys() {
+            callees.entry(from.as_str()).or_default().push(to.as_str());
+        }
+        let mut reaches = BTreeMap::new();
+        for function in functions.keys() {
+            // Walk the calls from the function, which doesn't reach itself unless it recurses
+            let mut reached: BTreeSet<&str> = BTreeSet::new();
+            let mut pending = vec![function.as_str()];
+            while let Some(caller) = pending.pop() {
+                for callee in callees.get(caller).into_iter().flatten() {
+                    if reached.insert(callee) {
+                        pending.push(callee);
+                    }
+                }
+            }
+            reaches.insert(
+                function.clone(),
+                reached.into_iter().map(String::from).collect(),
+            );
+        }
+        let entry = ["Sys.init", "Main.main"]
+            .into_iter()
+            .find(|entry| functions.contains_key(*entry))
+            .map(String::from);
+        CallGraph {
+            functions,
+            calls,
+            reaches,
+            entry,
+        }
+    }
+
+    fn kind(&self, function: &str) -> Kind {
+        if self.functions.contains_key(function) {
+            Kind::Defined
+        } else if os::defines(function) {
+            Kind::Os
+        } else {
+            Kind::Undefined
+        }
+    }
+
+    /// Whether a function can end up calling itself.
+    fn recursive(&self, function: &str) -> bool {
+        self.reaches
+            .get(function)
+            .is_some_and(|reached| reached.contains(function))
+    }
+
+    /// Whether a call is part of a cycle, so the callee can call back to the caller.
+    fn recursive_call(&self, from: &str, to: &str) -> bool {
+        self.reaches
+            .get(to)
+            .is_some_and(|reached| reached.contains(from))
+    }
+
+    /// Whether the program can call a function, going by its entry.
+    fn reachable(&self, function: &str) -> bool {
+        match &self.entry {
+            Some(entry) => function == entry || self.reaches[entry].contains(function),
+            None => true,
+        }
+    }
+
+    /// Everything named in the graph: what's defined, then what's only called.
+    fn names(&self) -> BTreeSet<&str> {
+        self.functions
+            .keys()
+            .chain(self.calls.keys().map(|(_, to)| to))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The groups of functions that call each other in a cycle.
+    fn cycles(&self) -> Vec<Vec<&str>> {
+        let mut cycles: Vec<Vec<&str>> = Vec::new();
+        for function in self.functions.keys() {
+            let grouped = cycles.iter().flatten().any(|other| other == function);
+            if grouped || !self.recursive(function) {
+                continue;
+            }
+            cycles.push(
+                self.reaches[function]
+                    .iter()
+                    .filter(|other| self.recursive_call(function, other))
+                    .map(String::as_str)
+                    .collect(),
+            );
+        }
+        cycles
+    }
+
+    /// The graph in Graphviz DOT.
+    ///
+    /// Recursive functions and the calls in their cycles are red, unreachable
+    /// functions are dashed, OS functions are grey boxes and undefined ones
+    /// are orange boxes. Calls made from more than one place are labelled
+    /// with how many.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph calls {\n");
+        for name in self.names() {
+            let mut attributes = Vec::new();
+            match self.kind(name) {
+                Kind::Defined => {
+                    let (file, line) = &self.functions[name];
+                    attributes.push(format!("tooltip=\"{file}:{line}\""));
+                }
+                Kind::Os => attributes.push(String::from("shape=box, color=grey")),
+                Kind::Undefined => attributes.push(String::from(
+                    "shape=box, color=orange, tooltip=\"undefined\"",
+                )),
+            }
+            if self.recursive(name) {
+                attributes.push(String::from("color=red"));
+            }
+            if !self.reachable(name) {
+                attributes.push(String::from("style=dashed"));
+            }
+            dot.push_str(&format!("    \"{name}\" [{}];\n", attributes.join(", ")));
+        }
+        for ((from, to), count) in &self.calls {
+            let mut attributes = Vec::new();
+            if *count > 1 {
+                attributes.push(format!("label={count}"));
+            }
+            if self.recursive_call(from, to) {
+                attributes.push(String::from("color=red"));
+            }
+            if attributes.is_empty() {
+                dot.push_str(&format!("    \"{from}\" -> \"{to}\";\n"));
+            } else {
+                dot.push_str(&format!(
+                    "    \"{from}\" -> \"{to}\" [{}];\n",
+                    attributes.join(", ")
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The graph as JSON, with `functions`, `calls` and `cycles`.
+    pub fn to_json(&self) -> Value {
+        let functions: Vec<Value> = self
+            .names()
+            .into_iter()
+            .map(|name| match self.kind(name) {
+                Kind::Defined => {
+                    let (file, line) = &self.functions[name];
+                    json!({
+                        "name": name,
+                        "kind": "function",
+                        "file": file,
+                        "line": line,
+                        "recursive": self.recursive(name),
+                        "reachable": self.reachable(name),
+                    })
+                }
+                kind => json!({
+                    "name": name,
+                    "kind": if kind == Kind::Os { "os" } else { "undefined" },
+                }),
+            })
+            .collect();
+        let calls: Vec<Value> = self
+            .calls
+            .iter()
+            .map(|((from, to), count)| {
+                json!({
+                    "from": from,
+                    "to": to,
+                    "count": count,
+                    "recursive": self.recursive_call(from, to),
+                })
+            })
+            .collect();
+        json!({
+            "entry": self.entry,
+            "functions": functions,
+            "calls": calls,
+            "cycles": self.cycles(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_cycles_unreachable_and_os_functions() {
+        let code = "function Main.main 0\ncall Main.even 1\ncall Math.abs 1\ncall Main.even 1\n\
+                    return\nfunction Main.even 0\ncall Main.odd 1\nreturn\n\
+                    function Main.odd 0\ncall Main.even 1\nreturn\n\
+                    function Main.unused 0\ncall Nowhere.f 0\nreturn\n";
+        let graph = CallGraph::build(&[(String::from(code), SourceMap::identity("Main"))]);
+        let json = graph.to_json();
+        assert_eq!(json["entry"], "Main.main");
+        assert_eq!(json["cycles"], json!([["Main.even", "Main.odd"]]));
+        let function = |name: &str| {
+            json["functions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|function| function["name"] == name)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(function("Main.odd")["recursive"], true);
+        assert_eq!(function("Main.main")["recursive"], false);
+        assert_eq!(function("Main.unused")["reachable"], false);
+        assert_eq!(function("Main.even")["line"], 6);
+        assert_eq!(function("Math.abs")["kind"], "os");
+        assert_eq!(function("Nowhere.f")["kind"], "undefined");
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("\"Main.main\" -> \"Main.even\" [label=2];"));
+        assert!(dot.contains("\"Main.even\" -> \"Main.odd\" [color=red];"));
+    }
+}
@@ -1,39 +1,57 @@
 #![allow(clippy::pedantic)]
 
+use crate::asm::{self, Address, AsmItem, Comp, Dest, Jump};
 use crate::parser::CommandType;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
+use indoc::indoc;
 use log::info;
 
-use indoc::formatdoc;
+const TRUE: Comp = Comp::MinusOne;
+const FALSE: Comp = Comp::Zero;
 
-const TRUE: i16 = -1;
-const FALSE: i16 = 0;
+/// Trade-off to make when there's more than one way to generate some code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OptimizeFor {
+    /// Fewest instructions executed
+    Speed,
+    /// Fewest instructions emitted
+    Size,
+}
 
-pub struct CodeWriter<'a> {
-    filename: &'a str,
-    op_lookup: HashMap<String, String>,
+pub struct CodeWriter {
+    filename: String,
+    op_lookup: HashMap<String, Comp>,
     memory_lookup: HashMap<String, String>,
     jmp_counter: i16,
-    call_counter: &'a mut i16,
+    call_counter: i16,
     current_function: String,
+    optimize: OptimizeFor,
+    /// Shared subroutines the code has called so far.
+    subroutines: BTreeSet<&'static str>,
+    /// Classes whose calls can be replaced by the hand-written code in INTRINSICS.
+    intrinsics: Vec<String>,
+    /// Highest SP allowed when checking the stack, or None to leave it unchecked.
+    stack_limit: Option<u16>,
+    /// Locals of the function being translated, which pops can't go below.
+    frame_vars: Option<i16>,
 }
 
-impl<'a> CodeWriter<'a> {
-    pub fn new(filename: &'a str, call_counter: &'a mut i16) -> Self {
+impl CodeWriter {
+    pub fn new(optimize: OptimizeFor, intrinsics: Vec<String>, stack_limit: Option<u16>) -> Self {
         CodeWriter {
-            filename,
+            filename: String::from("Sys"),
             op_lookup: HashMap::from([
-                (String::from("add"), String::from("+")),
-                (String::from("sub"), String::from("-")),
-                (String::from("neg"), String::from("-")),
+                (String::from("add"), Comp::DPlusM),
+                (String::from("sub"), Comp::MMinusD),
+                (String::from("neg"), Comp::NegD),
                 // Next 3 only care about jump insruction after the math
-                (String::from("eq"), String::from("-")),
-                (String::from("gt"), String::from("-")),
-                (String::from("lt"), String::from("-")),
-                (String::from("and"), String::from("&")),
-                (String::from("or"), String::from("|")),
-                (String::from("not"), String::from("!")),
+                (String::from("eq"), Comp::MMinusD),
+                (String::from("gt"), Comp::MMinusD),
+                (String::from("lt"), Comp::MMinusD),
+                (String::from("and"), Comp::DAndM),
+                (String::from("or"), Comp::DOrM),
+                (String::from("not"), Comp::NotD),
             ]),
             memory_lookup: HashMap::from([
                 (String::from("local"), String::from("LCL")),
@@ -43,466 +61,1726 @@ impl<'a> CodeWriter<'a> {
                 (String::from("temp"), String::from("TEMP")),
             ]),
             jmp_counter: 0,
-            call_counter,
+            call_counter: -1,
             current_function: String::from("bootstrap"),
+            optimize,
+            subroutines: BTreeSet::new(),
+            intrinsics,
+            stack_limit,
+            frame_vars: None,
         }
     }
 
-    pub fn write_label(&self, label: &str) -> String {
-        // TODO - Handle multiple files and knowing if inside a function when called.
-        // Track current function in code_writer
-        let write_string = formatdoc! {
-            "
-            // label {label}
-            ({}.{}${label})
-            ", self.filename, self.current_function
-        };
-        write_string
+    /// Start translating a new file, which scopes its statics and labels.
+    pub fn set_filename(&mut self, filename: &str) {
+        self.filename = String::from(filename);
+        self.current_function = String::from("bootstrap");
+        self.frame_vars = None;
     }
 
-    pub fn write_goto(&self, label: &str) -> String {
-        let write_string = formatdoc! {
-            "
-            // goto {}.{}${label}
-            @{}.{}${label}
-            0;JMP
+    /// Set SP to 256 and call the program's entry point.
+    pub fn write_bootstrap(&mut self, init_function: &str) -> Vec<AsmItem> {
+        let mut items = vec![
+            AsmItem::comment("bootstrap"),
+            AsmItem::value(256),
+            AsmItem::assign(Dest::D, Comp::A),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::M, Comp::D),
+        ];
+        // SP is known to be fine here, so there's nothing to check
+        items.extend(self.generate_call(init_function, 0));
+        items
+    }
 
-            ", self.filename, self.current_function, self.filename, self.current_function
-        };
-        write_string
+    /// Finish the program with an infinite loop.
+    pub fn write_infinite_loop(&self) -> Vec<AsmItem> {
+        vec![
+            AsmItem::comment("infinite loop"),
+            AsmItem::label("INFINITE_LOOP"),
+            AsmItem::symbol("INFINITE_LOOP"),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+        ]
     }
 
-    pub fn write_if(&self, label: &str) -> String {
-        let write_string = formatdoc! {
-            "
-            // if-goto {label}
-            {}
-            @{}.{}${label}
-            D;JNE
+    pub fn write_label(&self, label: &str) -> Vec<AsmItem> {
+        // TODO - Handle multiple files and knowing if inside a function when called.
+        // Track current function in code_writer
+        vec![
+            AsmItem::comment(format!("label {label}").as_str()),
+            AsmItem::label(self.scoped_label(label).as_str()),
+        ]
+    }
 
-            ", self.generate_pop_stack(true), self.filename, self.current_function
-        };
-        write_string
+    pub fn write_goto(&self, label: &str) -> Vec<AsmItem> {
+        vec![
+            AsmItem::comment(format!("goto {}", self.scoped_label(label)).as_str()),
+            AsmItem::symbol(self.scoped_label(label).as_str()),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+        ]
     }
 
-    pub fn write_function(&mut self, function_name: &str, n_vars: i16) -> String {
-        let mut write_string = formatdoc! {
-            "
-            // function {function_name} {n_vars}
-            ({function_name})
-            "
-        };
-        for _ in 0..n_vars {
-            write_string.push_str(
-                formatdoc! {"
-            @0
-            D=A
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            "}
-                .as_str(),
-            );
-        }
+    pub fn write_if(&mut self, label: &str) -> Vec<AsmItem> {
+        let mut items = vec![AsmItem::comment(format!("if-goto {label}").as_str())];
+        items.extend(self.generate_pop_check(1));
+        items.extend(self.generate_pop_stack(true));
+        items.push(AsmItem::symbol(self.scoped_label(label).as_str()));
+        items.push(AsmItem::jump(Comp::D, Jump::JNE));
+        items
+    }
+
+    pub fn write_function(&mut self, function_name: &str, n_vars: i16) -> Vec<AsmItem> {
+        let mut items = vec![
+            AsmItem::comment(format!("function {function_name} {n_vars}").as_str()),
+            AsmItem::label(function_name),
+        ];
+        items.extend(self.generate_init_locals(function_name, n_vars));
         self.current_function = String::from(function_name.split('.').nth(1).unwrap());
-        write_string.push_str("\n");
-        write_string
+        self.frame_vars = Some(n_vars);
+        if n_vars > 0 {
+            items.extend(self.generate_push_check("CHECK_PUSH"));
+        }
+        items
     }
 
-    pub fn write_call(&mut self, function_name: &str, n_args: i16) -> String {
+    pub fn write_call(&mut self, function_name: &str, n_args: i16) -> Vec<AsmItem> {
+        let check = self.generate_push_check("CHECK_CALL");
+        insert_check(self.generate_call(function_name, n_args), check)
+    }
+
+    fn generate_call(&mut self, function_name: &str, n_args: i16) -> Vec<AsmItem> {
         info!("function_name in call is {:?}", function_name);
-        *self.call_counter += 1;
-        let write_string = formatdoc! {
-            // TODO - Call other functions to improve this
-            "// call {function_name} {n_args}
-            // Generate return address label and push to stack
-            @{function_name}$ret.{}
-            D=A
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            // Push LCL
-            @LCL
-            D=M
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            // Push ARG
-            @ARG
-            D=M
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            // Push THIS
-            @THIS
-            D=M
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            // Push THAT
-            @THAT
-            D=M
-            @SP
-            A=M
-            M=D
-            @SP
-            M=M+1
-            // ARG = SP-5-nArgs
-            @5
-            D=A
-            @SP
-            D=M-D
-            @{n_args}
-            D=D-A
-            @ARG
-            M=D
-            // LCL = SP
-            @SP
-            D=M
-            @LCL
-            M=D
-            // goto {function_name}
-            @{function_name}
-            0;JMP
-            ({function_name}$ret.{})
-            
-            ", self.call_counter, self.call_counter,
-        };
+        self.call_counter += 1;
+        let return_label = format!("{function_name}$ret.{}", self.call_counter);
+        let comment = format!("call {function_name} {n_args} (intrinsic)");
+        match self.intrinsic(function_name, n_args) {
+            Some(Intrinsic::Inline(code)) => {
+                let mut items = vec![AsmItem::comment(comment.as_str())];
+                items.extend(asm::parse(code));
+                return items;
+            }
+            Some(Intrinsic::Subroutine(entry, subroutine)) => {
+                return self.generate_subroutine_jump(&comment, entry, subroutine, &return_label);
+            }
+            Some(Intrinsic::Before(_)) | None => {}
+        }
+        let mut items = self.generate_before_call(function_name, n_args);
+        if self.optimize == OptimizeFor::Size {
+            // Leave saving the frame to the shared VM_CALL
+            self.subroutines.insert(VM_CALL);
+            items.extend([
+                AsmItem::comment(format!("call {function_name} {n_args}").as_str()),
+                AsmItem::value(n_args as i32),
+                AsmItem::assign(Dest::D, Comp::A),
+                AsmItem::symbol("R13"),
+                AsmItem::assign(Dest::M, Comp::D),
+                AsmItem::symbol(function_name),
+                AsmItem::assign(Dest::D, Comp::A),
+                AsmItem::symbol("R14"),
+                AsmItem::assign(Dest::M, Comp::D),
+                AsmItem::symbol(return_label.as_str()),
+                AsmItem::assign(Dest::D, Comp::A),
+                AsmItem::symbol("VM_CALL"),
+                AsmItem::jump(Comp::Zero, Jump::JMP),
+                AsmItem::label(return_label.as_str()),
+            ]);
+            return items;
+        }
+        items.extend([
+            AsmItem::comment(format!("call {function_name} {n_args}").as_str()),
+            AsmItem::comment("Generate return address label and push to stack"),
+            AsmItem::symbol(return_label.as_str()),
+            AsmItem::assign(Dest::D, Comp::A),
+        ]);
+        items.extend(generate_push_d());
+        for segment in ["LCL", "ARG", "THIS", "THAT"] {
+            items.push(AsmItem::comment(format!("Push {segment}").as_str()));
+            items.push(AsmItem::symbol(segment));
+            items.push(AsmItem::assign(Dest::D, Comp::M));
+            items.extend(generate_push_d());
+        }
+        items.extend([
+            AsmItem::comment("ARG = SP-5-nArgs"),
+            AsmItem::value(5),
+            AsmItem::assign(Dest::D, Comp::A),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::D, Comp::MMinusD),
+            AsmItem::value(n_args as i32),
+            AsmItem::assign(Dest::D, Comp::DMinusA),
+            AsmItem::symbol("ARG"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment("LCL = SP"),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("LCL"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment(format!("goto {function_name}").as_str()),
+            AsmItem::symbol(function_name),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+            AsmItem::label(return_label.as_str()),
+        ]);
         info!(
             "after call statement, call_counter is now : {:?}",
             self.call_counter
         );
-        write_string
+        items
     }
 
-    pub fn write_return(&mut self) -> String {
-        // TODO: Call other functions to improve this.
-        let write_string = formatdoc! {
-            "// return
-            // Store LCL in frame
-            @LCL
-            D=M
-            @FRAME
-            M=D
-            // Store retAddress *(frame-5) in @RET
-            @5
-            D=D-A
-            @RET
-            AM=D
-            D=M
-            @RET
-            M=D
-            // Pop the return value for caller
-            {}
-            @ARG
-            A=M
-            M=D
-            // Restore caller's SP (ARG+1)
-            @ARG
-            D=M
-            D=D+1
-            @SP
-            M=D
-            // Restore THAT for caller *(frame-1)
-            @FRAME
-            D=M
-            A=M-1
-            D=M
-            @THAT
-            M=D
-            // Restore THIS for caller *(frame-2)
-            @2
-            D=A
-            @FRAME
-            D=M-D
-            A=D
-            D=M
-            @THIS
-            M=D
-            // Restore ARG for caller *(frame-3)
-            @3
-            D=A
-            @FRAME
-            D=M-D
-            A=D
-            D=M
-            @ARG
-            M=D
-            // Restore LCL for caller *(frame-4)
-            @4
-            D=A
-            @FRAME
-            D=M-D
-            A=D
-            D=M
-            @LCL
-            M=D
-            // goto return address
-            @RET
-            A=M
-            0;JMP
-            
-            ", self.generate_pop_stack(true)
+    /// Write a call in tail position, i.e. one immediately followed by `return`.
+    ///
+    /// Instead of pushing a new frame, the callee reuses the current one: the
+    /// saved frame and the new arguments are moved down over the current
+    /// arguments, so the callee returns straight to our caller. The saved
+    /// frame sits above the arguments for a moment, so the stack is checked
+    /// like it is for a call.
+    pub fn write_tail_call(&mut self, function_name: &str, n_args: i16) -> Vec<AsmItem> {
+        let check = self.generate_push_check("CHECK_CALL");
+        insert_check(self.generate_tail_call(function_name, n_args), check)
+    }
+
+    fn generate_tail_call(&mut self, function_name: &str, n_args: i16) -> Vec<AsmItem> {
+        info!("function_name in tail call is {:?}", function_name);
+        // The saved frame is copied above the new arguments first, making
+        // arguments and frame one contiguous block that can be moved down.
+        let mut items = self.generate_before_call(function_name, n_args);
+        items.extend([
+            AsmItem::comment(format!("tail call {function_name} {n_args}").as_str()),
+            AsmItem::comment("Copy the saved frame *(LCL-5)..*(LCL-1) to the top of the stack"),
+            AsmItem::symbol("LCL"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::value(5),
+            AsmItem::assign(Dest::D, Comp::DMinusA),
+            AsmItem::symbol("R13"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::M, Comp::D),
+        ]);
+        items.extend(generate_copy(5));
+        items.extend([
+            AsmItem::comment("Move the new arguments and saved frame down to ARG"),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::value(n_args as i32),
+            AsmItem::assign(Dest::D, Comp::DMinusA),
+            AsmItem::symbol("R13"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::symbol("ARG"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::M, Comp::D),
+        ]);
+        items.extend(generate_copy(n_args + 5));
+        items.extend([
+            AsmItem::comment("LCL = SP = ARG+nArgs+5"),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("LCL"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment(format!("goto {function_name}").as_str()),
+            AsmItem::symbol(function_name),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+        ]);
+        items
+    }
+
+    /// The intrinsic for a call, if the function has one and its class is linked.
+    fn intrinsic(&self, function_name: &str, n_args: i16) -> Option<&'static Intrinsic> {
+        let class = function_name.split('.').next().unwrap();
+        if !self.intrinsics.iter().any(|linked| linked == class) {
+            return None;
+        }
+        INTRINSICS
+            .iter()
+            .find(|(name, args, _)| *name == function_name && *args == n_args)
+            .map(|(_, _, intrinsic)| intrinsic)
+    }
+
+    /// The code an intrinsic runs before the function is called as usual, if it has any.
+    fn generate_before_call(&self, function_name: &str, n_args: i16) -> Vec<AsmItem> {
+        let Some(Intrinsic::Before(code)) = self.intrinsic(function_name, n_args) else {
+            return Vec::new();
         };
+        let mut items = vec![AsmItem::comment(
+            format!("{function_name} {n_args} (intrinsic, before the call)").as_str(),
+        )];
+        items.extend(asm::parse(code));
+        items
+    }
 
-        write_string
+    pub fn write_return(&mut self) -> Vec<AsmItem> {
+        // The return value has to be there
+        let check = self.generate_pop_check(1);
+        insert_check(self.generate_return(), check)
     }
 
-    pub fn write_arithmetic(&mut self, op: &str) -> String {
-        match op {
-            "add" | "sub" | "and" | "or" => {
-                self.generate_math_string(String::from(op), false, None)
-            }
-            "neg" | "not" => self.generate_math_string(String::from(op), true, None),
-            "eq" => self.generate_math_string(String::from("eq"), false, Some(String::from("JEQ"))),
-            "gt" => self.generate_math_string(String::from("gt"), false, Some(String::from("JGT"))),
-            "lt" => self.generate_math_string(String::from("lt"), false, Some(String::from("JLT"))),
+    fn generate_return(&mut self) -> Vec<AsmItem> {
+        if self.optimize == OptimizeFor::Size {
+            self.subroutines.insert(VM_RETURN);
+            return vec![
+                AsmItem::comment("return"),
+                AsmItem::symbol("VM_RETURN"),
+                AsmItem::jump(Comp::Zero, Jump::JMP),
+            ];
+        }
+        // TODO: Call other functions to improve this.
+        let mut items = vec![
+            AsmItem::comment("return"),
+            AsmItem::comment("Store LCL in frame"),
+            AsmItem::symbol("LCL"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("FRAME"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment("Store retAddress *(frame-5) in @RET"),
+            AsmItem::value(5),
+            AsmItem::assign(Dest::D, Comp::DMinusA),
+            AsmItem::symbol("RET"),
+            AsmItem::assign(Dest::AM, Comp::D),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("RET"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment("Pop the return value for caller"),
+        ];
+        items.extend(self.generate_pop_stack(true));
+        items.extend([
+            AsmItem::symbol("ARG"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment("Restore caller's SP (ARG+1)"),
+            AsmItem::symbol("ARG"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::assign(Dest::D, Comp::DPlusOne),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::comment("Restore THAT for caller *(frame-1)"),
+            AsmItem::symbol("FRAME"),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::assign(Dest::A, Comp::MMinusOne),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("THAT"),
+            AsmItem::assign(Dest::M, Comp::D),
+        ]);
+        for (segment, offset) in [("THIS", 2), ("ARG", 3), ("LCL", 4)] {
+            items.extend([
+                AsmItem::comment(
+                    format!("Restore {segment} for caller *(frame-{offset})").as_str(),
+                ),
+                AsmItem::value(offset),
+                AsmItem::assign(Dest::D, Comp::A),
+                AsmItem::symbol("FRAME"),
+                AsmItem::assign(Dest::D, Comp::MMinusD),
+                AsmItem::assign(Dest::A, Comp::D),
+                AsmItem::assign(Dest::D, Comp::M),
+                AsmItem::symbol(segment),
+                AsmItem::assign(Dest::M, Comp::D),
+            ]);
+        }
+        items.extend([
+            AsmItem::comment("goto return address"),
+            AsmItem::symbol("RET"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+        ]);
+        items
+    }
+
+    pub fn write_arithmetic(&mut self, op: &str) -> Vec<AsmItem> {
+        let check = self.generate_pop_check(if op == "neg" || op == "not" { 1 } else { 2 });
+        let items = match op {
+            "add" | "sub" | "and" | "or" => self.generate_math(String::from(op), false, None),
+            "neg" | "not" => self.generate_math(String::from(op), true, None),
+            "eq" => self.generate_math(String::from("eq"), false, Some(Jump::JEQ)),
+            "gt" => self.generate_math(String::from("gt"), false, Some(Jump::JGT)),
+            "lt" => self.generate_math(String::from("lt"), false, Some(Jump::JLT)),
+            "mul" => self.generate_subroutine_call(op, "EXT_MUL", MUL),
+            "div" => self.generate_subroutine_call(op, "EXT_DIV", DIV_MOD),
+            "mod" => self.generate_subroutine_call(op, "EXT_MOD", DIV_MOD),
+            "shl" => self.generate_subroutine_call(op, "EXT_SHL", SHL),
+            "shr" => self.generate_subroutine_call(op, "EXT_SHR", SHR),
             _ => {
                 panic!("Tried to do math on a not math ({:?}) thing!", op)
             }
+        };
+        insert_check(items, check)
+    }
+
+    /// The shared subroutines the code has used, to go after the program.
+    ///
+    /// Each one is only written if something calls it. Execution jumps over
+    /// them, in case the program doesn't end in an infinite loop.
+    pub fn write_subroutines(&self) -> Vec<AsmItem> {
+        if self.subroutines.is_empty() && self.stack_limit.is_none() {
+            return Vec::new();
+        }
+        let mut items = vec![
+            AsmItem::comment("shared subroutines"),
+            AsmItem::symbol("EXT_END"),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+        ];
+        for subroutine in &self.subroutines {
+            items.extend(asm::parse(subroutine));
+        }
+        if let Some(limit) = self.stack_limit {
+            items.extend(asm::parse(&stack_checks(limit)));
         }
+        items.push(AsmItem::label("EXT_END"));
+        items
     }
 
-    pub fn write_push_pop(&self, command_type: CommandType, segment: &str, index: i16) -> String {
+    pub fn write_push_pop(
+        &mut self,
+        command_type: CommandType,
+        segment: &str,
+        index: i16,
+    ) -> Vec<AsmItem> {
         match command_type {
             // TODO: move segment and index matching here, pass to push and pop
-            CommandType::PUSH => self.generate_push_string(segment, index),
-            CommandType::POP => self.generate_pop_string(segment, index),
+            CommandType::PUSH => {
+                let mut items = self.generate_push(segment, index);
+                items.extend(self.generate_push_check("CHECK_PUSH"));
+                items
+            }
+            CommandType::POP => {
+                let check = self.generate_pop_check(1);
+                insert_check(self.generate_pop(segment, index), check)
+            }
             _ => {
                 panic!("Error in matching what command to run in push_pop!")
             }
         }
     }
 
-    /// Generate a string of hack asm to pop the value off the stack
+    /// Write a block of hand-written Hack assembly from an asm block.
+    ///
+    /// Labels declared in the block are scoped to the function like VM labels,
+    /// so the same block can appear in more than one function. Every other
+    /// symbol is left alone.
+    pub fn write_asm(&self, lines: &[String]) -> Vec<AsmItem> {
+        let mut items = asm::parse(&lines.join("\n"));
+        let labels: Vec<String> = items
+            .iter()
+            .filter_map(|item| match item {
+                AsmItem::Label(label) => Some(label.clone()),
+                _ => None,
+            })
+            .collect();
+        for item in items.iter_mut() {
+            match item {
+                AsmItem::Label(label) => *label = self.scoped_label(label),
+                AsmItem::AInstr(Address::Symbol(symbol)) if labels.contains(symbol) => {
+                    *symbol = self.scoped_label(symbol)
+                }
+                _ => {}
+            }
+        }
+        items.insert(0, AsmItem::comment("asm"));
+        items
+    }
+
+    /// Labels are scoped to the file and function they're declared in.
+    fn scoped_label(&self, label: &str) -> String {
+        format!("{}.{}${label}", self.filename, self.current_function)
+    }
+
+    /// Generate hack asm to push `n_vars` zeroed locals.
+    ///
+    /// A couple of locals are cheapest pushed one at a time. Past that, speed
+    /// zeroes each slot at its offset from SP and bumps SP once at the end,
+    /// while size uses a fixed-length loop.
+    fn generate_init_locals(&self, function_name: &str, n_vars: i16) -> Vec<AsmItem> {
+        let mut items = Vec::new();
+        if n_vars <= 0 {
+            return items;
+        }
+        if n_vars <= 2 {
+            for _ in 0..n_vars {
+                items.extend([
+                    AsmItem::symbol("SP"),
+                    AsmItem::assign(Dest::AM, Comp::MPlusOne),
+                    AsmItem::assign(Dest::A, Comp::AMinusOne),
+                    AsmItem::assign(Dest::M, Comp::Zero),
+                ]);
+            }
+            return items;
+        }
+        match self.optimize {
+            OptimizeFor::Speed => {
+                items.extend([
+                    AsmItem::symbol("SP"),
+                    AsmItem::assign(Dest::A, Comp::M),
+                    AsmItem::assign(Dest::M, Comp::Zero),
+                ]);
+                for _ in 1..n_vars {
+                    items.push(AsmItem::assign(Dest::A, Comp::APlusOne));
+                    items.push(AsmItem::assign(Dest::M, Comp::Zero));
+                }
+                items.extend([
+                    AsmItem::value(n_vars as i32),
+                    AsmItem::assign(Dest::D, Comp::A),
+                    AsmItem::symbol("SP"),
+                    AsmItem::assign(Dest::M, Comp::DPlusM),
+                ]);
+            }
+            OptimizeFor::Size => {
+                let loop_label = format!("{function_name}$INIT_LOCALS");
+                items.extend([
+                    AsmItem::value(n_vars as i32),
+                    AsmItem::assign(Dest::D, Comp::A),
+                    AsmItem::label(loop_label.as_str()),
+                    AsmItem::symbol("SP"),
+                    AsmItem::assign(Dest::AM, Comp::MPlusOne),
+                    AsmItem::assign(Dest::A, Comp::AMinusOne),
+                    AsmItem::assign(Dest::M, Comp::Zero),
+                    AsmItem::assign(Dest::D, Comp::DMinusOne),
+                    AsmItem::symbol(loop_label.as_str()),
+                    AsmItem::jump(Comp::D, Jump::JGT),
+                ]);
+            }
+        }
+        items
+    }
+
+    /// Generate hack asm to pop the value off the stack
     ///
     /// # Arguments
     /// * `store_d` - if true, store the popped value in D
-    fn generate_pop_stack(&self, store_d: bool) -> String {
+    fn generate_pop_stack(&self, store_d: bool) -> Vec<AsmItem> {
         // AM=M-1 is the shorter version of
         //
         // M=M=1
         // A=M
-        let write_string = formatdoc! {
-            "
-            @SP
-            AM=M-1"
-        };
+        let mut items = vec![
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::AM, Comp::MMinusOne),
+        ];
         if store_d {
-            return formatdoc! {"
-            {}
-            D=M // Grab element-- from memory", write_string};
+            // Grab element-- from memory
+            items.push(AsmItem::assign(Dest::D, Comp::M));
         }
-        write_string
+        items
     }
 
-    fn generate_push_string(&self, segment: &str, index: i16) -> String {
-        let comment_string = format!("// push {segment} {index}");
-        let common_string = formatdoc!(
-            "@SP
-            A=M // Go to Stack pointer
-            M=D // Set RAM[SP] equal to D"
-        );
+    fn generate_push(&self, segment: &str, index: i16) -> Vec<AsmItem> {
+        let mut items = vec![AsmItem::comment(format!("push {segment} {index}").as_str())];
         // constant doesn't need to store in any memory
         if segment == "constant" {
-            let write_string = formatdoc! {
-                "{}
-                 @{index}
-                 D=A
-                 {}", comment_string, common_string
-            };
-            increment_stack_pointer(&write_string)
+            match index {
+                // Hack can write these straight to memory
+                -1..=1 => {
+                    items.extend([
+                        AsmItem::symbol("SP"),
+                        AsmItem::assign(Dest::AM, Comp::MPlusOne),
+                        AsmItem::assign(Dest::A, Comp::AMinusOne),
+                        AsmItem::assign(Dest::M, constant_comp(index)),
+                    ]);
+                    return items;
+                }
+                2.. => {
+                    items.push(AsmItem::value(index as i32));
+                    items.push(AsmItem::assign(Dest::D, Comp::A));
+                }
+                // -32768 is the one negative number whose negation doesn't fit in @x
+                i16::MIN => {
+                    items.push(AsmItem::value(i16::MAX as i32));
+                    items.push(AsmItem::assign(Dest::D, Comp::NotA));
+                }
+                _ => {
+                    items.push(AsmItem::value(-(index as i32)));
+                    items.push(AsmItem::assign(Dest::D, Comp::NegA));
+                }
+            }
         } else if segment == "static" {
-            let write_string = formatdoc! {
-                "{comment_string}
-                @{}.{index}
-                D=M
-                {common_string}", self.filename
-            };
-            increment_stack_pointer(&write_string)
+            items.push(AsmItem::symbol(
+                format!("{}.{index}", self.filename).as_str(),
+            ));
+            items.push(AsmItem::assign(Dest::D, Comp::M));
         } else if segment == "temp" {
-            let write_string = formatdoc! {
-                "{comment_string}
-                @{}
-                D=M
-                {}", index + 5, common_string
-            };
-            increment_stack_pointer(&write_string)
-        } else {
+            items.push(AsmItem::value(index as i32 + 5));
+            items.push(AsmItem::assign(Dest::D, Comp::M));
+        } else if segment == "pointer" {
             // pointer 0 == THIS
             // pointer 1 == THAT
             // push pointer 0 pushes THIS's value to the stack
-            if segment == "pointer" {
-                let ptr_segment: &str;
-                if index == 0 {
-                    ptr_segment = "this";
-                } else if index == 1 {
-                    ptr_segment = "that";
-                } else {
-                    panic!("pointer can only be 0 or 1!");
-                }
-                let write_string = formatdoc! {
-                    "{comment_string}
-                    @{}
-                    D=M
-                    {common_string}", self.memory_lookup[ptr_segment]
-                };
-
-                return increment_stack_pointer(&write_string);
-            }
-            let write_string = formatdoc!(
-                "{comment_string}
-                @{}
-                D=M // Store RAM location
-                @{index}
-                A=D+A // Go to RAM + Offset
-                D=M // Get RAM[index] in D
-                {common_string}",
-                self.memory_lookup[segment],
-            );
-            increment_stack_pointer(&write_string)
+            items.push(AsmItem::symbol(&self.memory_lookup[pointer_segment(index)]));
+            items.push(AsmItem::assign(Dest::D, Comp::M));
+        } else {
+            items.extend([
+                // Store RAM location
+                AsmItem::symbol(&self.memory_lookup[segment]),
+                AsmItem::assign(Dest::D, Comp::M),
+                // Go to RAM + Offset
+                AsmItem::value(index as i32),
+                AsmItem::assign(Dest::A, Comp::DPlusA),
+                // Get RAM[index] in D
+                AsmItem::assign(Dest::D, Comp::M),
+            ]);
         }
+        items.extend(generate_push_d());
+        items
     }
 
-    fn generate_pop_string(&self, segment: &str, index: i16) -> String {
-        let comment_string = format!("// pop {segment} {index}");
+    fn generate_pop(&self, segment: &str, index: i16) -> Vec<AsmItem> {
+        let mut items = vec![AsmItem::comment(format!("pop {segment} {index}").as_str())];
 
         if segment == "constant" {
             panic!("Can't pop constant!")
         } else if segment == "static" {
-            let common_string = formatdoc! {
-                "{comment_string}
-                {}
-                @{}.{index}
-                M=D
-                
-                ", self.generate_pop_stack(true), self.filename
-            };
-            common_string
+            items.extend(self.generate_pop_stack(true));
+            items.push(AsmItem::symbol(
+                format!("{}.{index}", self.filename).as_str(),
+            ));
         } else if segment == "temp" {
-            let common_string = formatdoc! {
-                "{comment_string}
-                {}
-                @{}
-                M=D
-                
-                ", self.generate_pop_stack(true), index + 5
-            };
-            common_string
-        } else {
+            items.extend(self.generate_pop_stack(true));
+            items.push(AsmItem::value(index as i32 + 5));
+        } else if segment == "pointer" {
             // pop pointer 0 sets THIS's memory to the stack value
-            if segment == "pointer" {
-                let ptr_segment: &str;
-                if index == 0 {
-                    ptr_segment = "this";
-                } else if index == 1 {
-                    ptr_segment = "that";
-                } else {
-                    panic!("pointer can only be 0 or 1!");
-                }
-                let common_string = formatdoc! {
-                    "{comment_string}
-                    {}
-                    @{}
-                    M=D
-
-                    ", self.generate_pop_stack(true), self.memory_lookup[ptr_segment]
-                };
-                return common_string;
-            }
-
+            items.extend(self.generate_pop_stack(true));
+            items.push(AsmItem::symbol(&self.memory_lookup[pointer_segment(index)]));
+        } else {
             // A=D+A
             // D=A // D contains RAM + Offset
             // equals
             //
             // AD=D+A
-            let common_string = formatdoc! {
-                "{comment_string}
-                @{}
-                D=M
-                @{index}
-                AD=D+A
-                @R13
-                M=D // Temp store RAM + Offset
-                {}
-                @R13
-                A=M // Jump to RAM + Offset
-                M=D
-                
-                ", self.memory_lookup[segment], self.generate_pop_stack(true)
-            };
-            common_string
+            items.extend([
+                AsmItem::symbol(&self.memory_lookup[segment]),
+                AsmItem::assign(Dest::D, Comp::M),
+                AsmItem::value(index as i32),
+                AsmItem::assign(Dest::AD, Comp::DPlusA),
+                // Temp store RAM + Offset
+                AsmItem::symbol("R13"),
+                AsmItem::assign(Dest::M, Comp::D),
+            ]);
+            items.extend(self.generate_pop_stack(true));
+            // Jump to RAM + Offset
+            items.push(AsmItem::symbol("R13"));
+            items.push(AsmItem::assign(Dest::A, Comp::M));
         }
+        items.push(AsmItem::assign(Dest::M, Comp::D));
+        items
     }
 
-    /// Generate a string of commands to update
+    /// Generate the commands to update
     /// @SP-2 with the calculated math operation that was performed.
-    fn generate_math_string(&mut self, op: String, unary: bool, jump: Option<String>) -> String {
-        let mut common_string = formatdoc! {
-            "// {op}
-            {}
-            ", self.generate_pop_stack(true)
-        };
+    fn generate_math(&mut self, op: String, unary: bool, jump: Option<Jump>) -> Vec<AsmItem> {
+        let mut items = vec![AsmItem::comment(op.as_str())];
+        items.extend(self.generate_pop_stack(true));
         if !unary {
-            common_string.push_str(self.generate_pop_stack(false).as_str());
-            common_string.push_str("\n");
+            items.extend(self.generate_pop_stack(false));
         }
-        match jump {
-            Some(_) => common_string.push_str("D="),
-            _ => common_string.push_str("M="),
-        } // D if jump, M if math.
-        if !unary {
-            common_string.push_str("M");
+        // D if jump, M if math.
+        let dest = match jump {
+            Some(_) => Dest::D,
+            _ => Dest::M,
+        };
+        items.push(AsmItem::assign(dest, self.op_lookup[&op]));
+        if let Some(j) = jump {
+            items.extend(self.generate_jump(j));
         }
-        common_string.push_str(format!("{}D", self.op_lookup[&op]).as_str());
-        match jump {
-            Some(j) => common_string.push_str(self.generate_jump_string(j).as_str()),
-            None => {}
+        items.extend(increment_stack_pointer());
+        items
+    }
+
+    /// Call a shared subroutine for an extended arithmetic command.
+    ///
+    /// The return address goes in D, and the subroutine pops its operands and
+    /// pushes the result itself.
+    fn generate_subroutine_call(
+        &mut self,
+        op: &str,
+        entry: &str,
+        subroutine: &'static str,
+    ) -> Vec<AsmItem> {
+        let return_label = format!("EXT_RET_{}", self.jmp_counter);
+        self.jmp_counter += 1;
+        self.generate_subroutine_jump(op, entry, subroutine, &return_label)
+    }
+
+    /// Jump to a shared subroutine that comes back to `return_label`.
+    fn generate_subroutine_jump(
+        &mut self,
+        comment: &str,
+        entry: &str,
+        subroutine: &'static str,
+        return_label: &str,
+    ) -> Vec<AsmItem> {
+        self.subroutines.insert(subroutine);
+        self.subroutines.insert(EXT_RETURN);
+        if subroutine == SHL || subroutine == SHR {
+            self.subroutines.insert(SHIFT_ZERO);
         }
-        increment_stack_pointer(&common_string)
+        vec![
+            AsmItem::comment(comment),
+            AsmItem::symbol(return_label),
+            AsmItem::assign(Dest::D, Comp::A),
+            AsmItem::symbol(entry),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+            AsmItem::label(return_label),
+        ]
     }
 
-    fn generate_jump_string(&mut self, jump: String) -> String {
-        let common_string = formatdoc! {
-            "
-            
-            @TRUE_{}
-            D;{jump}
+    /// Check SP is within the stack limit, with `entry` one of the checks in `stack_checks`.
+    ///
+    /// Pushes are checked after SP has gone up, and calls before they push
+    /// their frame. Nothing is generated unless the stack is being checked.
+    fn generate_push_check(&mut self, entry: &str) -> Vec<AsmItem> {
+        if self.stack_limit.is_none() {
+            return Vec::new();
+        }
+        self.generate_check_jump(entry)
+    }
+
+    /// Check the stack holds `words` values before they're popped.
+    ///
+    /// In a function they have to be above its locals, and anywhere else
+    /// above 256.
+    fn generate_pop_check(&mut self, words: i16) -> Vec<AsmItem> {
+        if self.stack_limit.is_none() {
+            return Vec::new();
+        }
+        let (words, entry) = match self.frame_vars {
+            Some(n_vars) => (n_vars + words, "CHECK_POP_FRAME"),
+            None => (words, "CHECK_POP"),
+        };
+        let mut items = vec![
+            AsmItem::value(words as i32),
+            AsmItem::assign(Dest::D, Comp::A),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::M, Comp::D),
+        ];
+        items.extend(self.generate_check_jump(entry));
+        items
+    }
+
+    /// Jump to a stack check, which comes back to the address passed in D.
+    fn generate_check_jump(&mut self, entry: &str) -> Vec<AsmItem> {
+        let return_label = format!("CHECK_RET_{}", self.jmp_counter);
+        self.jmp_counter += 1;
+        vec![
+            AsmItem::symbol(return_label.as_str()),
+            AsmItem::assign(Dest::D, Comp::A),
+            AsmItem::symbol(entry),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+            AsmItem::label(return_label.as_str()),
+        ]
+    }
+
+    fn generate_jump(&mut self, jump: Jump) -> Vec<AsmItem> {
+        let true_label = format!("TRUE_{}", self.jmp_counter);
+        let false_label = format!("FALSE_{}", self.jmp_counter);
+        self.jmp_counter += 1;
+        vec![
+            AsmItem::symbol(true_label.as_str()),
+            AsmItem::jump(Comp::D, jump),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::assign(Dest::M, FALSE),
+            AsmItem::symbol(false_label.as_str()),
+            AsmItem::jump(Comp::Zero, Jump::JMP),
+            AsmItem::label(true_label.as_str()),
+            AsmItem::symbol("SP"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::assign(Dest::M, TRUE),
+            AsmItem::label(false_label.as_str()),
+        ]
+    }
+}
+
+/// The computation giving 0, 1 or -1.
+fn constant_comp(value: i16) -> Comp {
+    match value {
+        0 => Comp::Zero,
+        1 => Comp::One,
+        -1 => Comp::MinusOne,
+        _ => panic!("{} isn't a constant Hack can compute!", value),
+    }
+}
+
+/// Put a stack check before a command's code, after its comment.
+fn insert_check(mut items: Vec<AsmItem>, check: Vec<AsmItem>) -> Vec<AsmItem> {
+    let at = usize::from(matches!(items.first(), Some(AsmItem::Comment(_))));
+    items.splice(at..at, check);
+    items
+}
+
+/// Map `pointer 0` to this and `pointer 1` to that.
+fn pointer_segment(index: i16) -> &'static str {
+    match index {
+        0 => "this",
+        1 => "that",
+        _ => panic!("pointer can only be 0 or 1!"),
+    }
+}
+
+/// Generate the opcodes to push D onto the stack.
+fn generate_push_d() -> Vec<AsmItem> {
+    let mut items = vec![
+        // Go to Stack pointer
+        AsmItem::symbol("SP"),
+        AsmItem::assign(Dest::A, Comp::M),
+        // Set RAM[SP] equal to D
+        AsmItem::assign(Dest::M, Comp::D),
+    ];
+    items.extend(increment_stack_pointer());
+    items
+}
+
+/// Generate the opcodes to increment the stack pointer.
+fn increment_stack_pointer() -> Vec<AsmItem> {
+    vec![
+        AsmItem::symbol("SP"),
+        AsmItem::assign(Dest::M, Comp::MPlusOne),
+    ]
+}
+
+/// Generate the opcodes to copy `count` words from RAM[R13] upwards to RAM[R14].
+fn generate_copy(count: i16) -> Vec<AsmItem> {
+    let mut items = Vec::new();
+    for _ in 0..count {
+        items.extend([
+            AsmItem::symbol("R13"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::assign(Dest::D, Comp::M),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::A, Comp::M),
+            AsmItem::assign(Dest::M, Comp::D),
+            AsmItem::symbol("R13"),
+            AsmItem::assign(Dest::M, Comp::MPlusOne),
+            AsmItem::symbol("R14"),
+            AsmItem::assign(Dest::M, Comp::MPlusOne),
+        ]);
+    }
+    items
+}
+
+/// Calls the function at the address in R14 with R13 arguments, returning to the address in D.
+///
+/// The same as the code write_call() generates for speed.
+const VM_CALL: &str = indoc! {"
+    (VM_CALL)
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    @LCL
+    D=M
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    @ARG
+    D=M
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    @THIS
+    D=M
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    @THAT
+    D=M
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    // ARG = SP-5-nArgs
+    @R13
+    D=M
+    @5
+    D=D+A
+    @SP
+    D=M-D
+    @ARG
+    M=D
+    // LCL = SP
+    @SP
+    D=M
+    @LCL
+    M=D
+    @R14
+    A=M
+    0;JMP
+"};
+
+/// Returns from the current function.
+///
+/// The same as the code write_return() generates for speed.
+const VM_RETURN: &str = indoc! {"
+    (VM_RETURN)
+    @LCL
+    D=M
+    @FRAME
+    M=D
+    @5
+    D=D-A
+    @RET
+    AM=D
+    D=M
+    @RET
+    M=D
+    // Pop the return value for caller
+    @SP
+    AM=M-1
+    D=M
+    @ARG
+    A=M
+    M=D
+    // Restore caller's SP (ARG+1)
+    @ARG
+    D=M
+    D=D+1
+    @SP
+    M=D
+    @FRAME
+    D=M
+    A=M-1
+    D=M
+    @THAT
+    M=D
+    @2
+    D=A
+    @FRAME
+    D=M-D
+    A=D
+    D=M
+    @THIS
+    M=D
+    @3
+    D=A
+    @FRAME
+    D=M-D
+    A=D
+    D=M
+    @ARG
+    M=D
+    @4
+    D=A
+    @FRAME
+    D=M-D
+    A=D
+    D=M
+    @LCL
+    M=D
+    @RET
+    A=M
+    0;JMP
+"};
+
+/// RAM address where a failed stack check leaves its error code.
+///
+/// This and TRAP_PC are the last two words below the screen, which the
+/// bundled OS's Memory leaves out of the heap.
+const TRAP_CODE: u16 = 16382;
+/// RAM address where a failed stack check leaves the ROM address of the check.
+const TRAP_PC: u16 = 16383;
+/// Error codes for failed stack checks.
+const STACK_OVERFLOW: u16 = 1;
+const STACK_UNDERFLOW: u16 = 2;
+
+/// The stack checks for `--checked`, returning to the address in D.
+///
+/// A failed check leaves its error code in TRAP_CODE and the address of the
+/// jump to the check in TRAP_PC, then halts. `R14` holds how many values a
+/// pop needs above the base.
+fn stack_checks(limit: u16) -> String {
+    format!(
+        indoc! {"
+            // Room for the values just pushed
+            (CHECK_PUSH)
+            @R15
+            M=D
             @SP
+            D=M
+            (CHECK_LIMIT)
+            @{limit}
+            D=D-A
+            @CHECK_OVERFLOW
+            D;JGT
+            @R15
             A=M
-            M={FALSE}
-            @FALSE_{}
             0;JMP
-            (TRUE_{})
+            // Room for the frame a call is about to push
+            (CHECK_CALL)
+            @R15
+            M=D
             @SP
+            D=M
+            @5
+            D=D+A
+            @CHECK_LIMIT
+            0;JMP
+            // Enough values above the function's locals
+            (CHECK_POP_FRAME)
+            @R15
+            M=D
+            @LCL
+            D=M
+            @CHECK_BASE
+            0;JMP
+            // Enough values above the bottom of the stack
+            (CHECK_POP)
+            @R15
+            M=D
+            @256
+            D=A
+            (CHECK_BASE)
+            @R14
+            D=D+M
+            @SP
+            D=M-D
+            @CHECK_UNDERFLOW
+            D;JLT
+            @R15
             A=M
-            M={TRUE}
-            (FALSE_{})", self.jmp_counter, self.jmp_counter, self.jmp_counter, self.jmp_counter
+            0;JMP
+            (CHECK_OVERFLOW)
+            @{overflow}
+            D=A
+            @CHECK_TRAP
+            0;JMP
+            (CHECK_UNDERFLOW)
+            @{underflow}
+            D=A
+            (CHECK_TRAP)
+            @{code}
+            M=D
+            @R15
+            D=M-1
+            @{pc}
+            M=D
+            (CHECK_HALT)
+            @CHECK_HALT
+            0;JMP
+        "},
+        limit = limit,
+        overflow = STACK_OVERFLOW,
+        underflow = STACK_UNDERFLOW,
+        code = TRAP_CODE,
+        pc = TRAP_PC,
+    )
+}
+
+/// Hand-written code for a function, used in place of calling it.
+enum Intrinsic {
+    /// Code written out at each call.
+    Inline(&'static str),
+    /// A shared subroutine with its entry label, called like the extended arithmetic ones.
+    Subroutine(&'static str, &'static str),
+    /// Code written out before calling the function as usual.
+    Before(&'static str),
+}
+
+/// Whether calls to a function can be replaced by, or go through, an intrinsic.
+pub fn has_intrinsic(function_name: &str) -> bool {
+    INTRINSICS.iter().any(|(name, _, _)| *name == function_name)
+}
+
+/// Functions of the Jack OS with an intrinsic, and how many arguments they take.
+///
+/// Each pops its arguments and pushes its result like the function would,
+/// but without saving and restoring a frame. The multiplication and division
+/// are the extended arithmetic ones, so dividing by zero gives 0 rather than
+/// stopping with error 3.
+const INTRINSICS: [(&str, i16, Intrinsic); 6] = [
+    ("Math.multiply", 2, Intrinsic::Subroutine("EXT_MUL", MUL)),
+    ("Math.divide", 2, Intrinsic::Subroutine("EXT_DIV", DIV_MOD)),
+    ("Memory.peek", 1, Intrinsic::Inline(PEEK)),
+    ("Memory.poke", 2, Intrinsic::Inline(POKE)),
+    (
+        "Screen.drawPixel",
+        2,
+        Intrinsic::Subroutine("INTRINSIC_DRAW_PIXEL", DRAW_PIXEL),
+    ),
+    ("Screen.setColor", 1, Intrinsic::Before(SET_COLOR)),
+];
+
+/// Memory.peek(address): replaces the address on the stack with what's there.
+const PEEK: &str = indoc! {"
+    @SP
+    A=M-1
+    A=M
+    D=M
+    @SP
+    A=M-1
+    M=D
+"};
+
+/// Memory.poke(address, value), leaving the 0 a void function returns.
+const POKE: &str = indoc! {"
+    @SP
+    AM=M-1
+    D=M
+    @SP
+    A=M-1
+    A=M
+    M=D
+    @SP
+    A=M-1
+    M=0
+"};
+
+/// Screen.setColor(b), before it's called as usual, so drawPixel knows the color.
+///
+/// INTRINSIC_COLOR is set to !(b | -b), which is negative only when b is 0,
+/// for white. So the 0 it starts as is black, like Screen.init sets.
+const SET_COLOR: &str = indoc! {"
+    @SP
+    A=M-1
+    D=-M
+    D=D|M
+    D=!D
+    @INTRINSIC_COLOR
+    M=D
+"};
+
+/// Screen.drawPixel(x, y), in the color last given to Screen.setColor.
+///
+/// Pixels off the screen are left alone rather than stopping with error 7.
+const DRAW_PIXEL: &str = indoc! {"
+    (INTRINSIC_DRAW_PIXEL)
+    @R15
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @EXT_Y
+    M=D
+    // x stays on the stack, to be replaced by the result
+    @SP
+    A=M-1
+    D=M
+    @EXT_X
+    M=D
+    @INTRINSIC_DRAW_PIXEL_DONE
+    D;JLT
+    @512
+    D=D-A
+    @INTRINSIC_DRAW_PIXEL_DONE
+    D;JGE
+    @EXT_Y
+    D=M
+    @INTRINSIC_DRAW_PIXEL_DONE
+    D;JLT
+    @256
+    D=D-A
+    @INTRINSIC_DRAW_PIXEL_DONE
+    D;JGE
+    // The word is at SCREEN + y*32 + x/16
+    @EXT_Y
+    D=M
+    @EXT_R
+    M=D
+    D=D+M
+    M=D
+    D=D+M
+    M=D
+    D=D+M
+    M=D
+    D=D+M
+    M=D
+    D=D+M
+    @SCREEN
+    D=D+A
+    @EXT_R
+    M=D
+    // Add x/16 a bit at a time, EXT_BIT going from 16 to 256 and EXT_Q from 1 to 16
+    @16
+    D=A
+    @EXT_BIT
+    M=D
+    @EXT_Q
+    M=1
+    (INTRINSIC_DRAW_PIXEL_WORD)
+    @EXT_BIT
+    D=M
+    @EXT_X
+    D=D&M
+    @INTRINSIC_DRAW_PIXEL_NEXT
+    D;JEQ
+    @EXT_Q
+    D=M
+    @EXT_R
+    M=D+M
+    (INTRINSIC_DRAW_PIXEL_NEXT)
+    @EXT_Q
+    D=M
+    M=D+M
+    @EXT_BIT
+    D=M
+    M=D+M
+    @512
+    D=D-A
+    @INTRINSIC_DRAW_PIXEL_WORD
+    D;JNE
+    // The pixel's bit is 1 shifted left by x%16
+    @15
+    D=A
+    @EXT_X
+    D=D&M
+    @EXT_N
+    M=D
+    @EXT_BIT
+    M=1
+    (INTRINSIC_DRAW_PIXEL_MASK)
+    @EXT_N
+    D=M
+    @INTRINSIC_DRAW_PIXEL_PLOT
+    D;JEQ
+    @EXT_N
+    M=D-1
+    @EXT_BIT
+    D=M
+    M=D+M
+    @INTRINSIC_DRAW_PIXEL_MASK
+    0;JMP
+    (INTRINSIC_DRAW_PIXEL_PLOT)
+    @INTRINSIC_COLOR
+    D=M
+    @INTRINSIC_DRAW_PIXEL_WHITE
+    D;JLT
+    @EXT_BIT
+    D=M
+    @EXT_R
+    A=M
+    M=D|M
+    @INTRINSIC_DRAW_PIXEL_DONE
+    0;JMP
+    (INTRINSIC_DRAW_PIXEL_WHITE)
+    @EXT_BIT
+    D=!M
+    @EXT_R
+    A=M
+    M=D&M
+    (INTRINSIC_DRAW_PIXEL_DONE)
+    @SP
+    A=M-1
+    M=0
+    @R15
+    A=M
+    0;JMP
+"};
+
+/// Pushes the result in D and jumps back to the address in R15.
+const EXT_RETURN: &str = indoc! {"
+    (EXT_RETURN)
+    @SP
+    A=M
+    M=D
+    @SP
+    M=M+1
+    @R15
+    A=M
+    0;JMP
+"};
+
+/// x*y, adding x shifted left for each bit set in y.
+///
+/// Stops as soon as there are no more bits set in y.
+const MUL: &str = indoc! {"
+    // mul: x*y
+    (EXT_MUL)
+    @R15
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @EXT_Y
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @EXT_X
+    M=D
+    @EXT_R
+    M=0
+    @EXT_BIT
+    M=1
+    (EXT_MUL_LOOP)
+    // Done once y has no bits at or above the current one
+    @EXT_BIT
+    D=-M
+    @EXT_Y
+    D=D&M
+    @EXT_MUL_DONE
+    D;JEQ
+    @EXT_BIT
+    D=M
+    @EXT_Y
+    D=D&M
+    @EXT_MUL_SKIP
+    D;JEQ
+    @EXT_X
+    D=M
+    @EXT_R
+    M=D+M
+    (EXT_MUL_SKIP)
+    @EXT_X
+    D=M
+    M=D+M
+    @EXT_BIT
+    D=M
+    M=D+M
+    @EXT_MUL_LOOP
+    0;JMP
+    (EXT_MUL_DONE)
+    @EXT_R
+    D=M
+    @EXT_RETURN
+    0;JMP
+"};
+
+/// x/y and x%y, rounding towards zero so the remainder has the sign of x.
+///
+/// Dividing by zero gives 0, leaving x as the remainder. Otherwise this is
+/// binary long division on the magnitudes, one bit of x at a time.
+const DIV_MOD: &str = indoc! {"
+    // mod: x%y
+    (EXT_MOD)
+    @R15
+    M=D
+    @R14
+    M=1
+    @EXT_DIVIDE
+    0;JMP
+    // div: x/y
+    (EXT_DIV)
+    @R15
+    M=D
+    @R14
+    M=0
+    (EXT_DIVIDE)
+    @SP
+    AM=M-1
+    D=M
+    @EXT_Y
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @EXT_X
+    M=D
+    @EXT_R
+    M=D
+    @EXT_Q
+    M=0
+    @EXT_Y
+    D=M
+    @EXT_DIV_RESULT
+    D;JEQ
+    // -32768 has no positive magnitude, so only divides itself
+    @32767
+    D=!A
+    @EXT_Y
+    D=D-M
+    @EXT_DIV_BY_MIN
+    D;JEQ
+    @EXT_X
+    D=M
+    @EXT_N
+    M=D
+    @EXT_DIV_X_MAGNITUDE
+    D;JGE
+    @EXT_N
+    M=-M
+    (EXT_DIV_X_MAGNITUDE)
+    @EXT_Y
+    D=M
+    @EXT_D
+    M=D
+    @EXT_DIV_Y_MAGNITUDE
+    D;JGE
+    @EXT_D
+    M=-M
+    (EXT_DIV_Y_MAGNITUDE)
+    @EXT_R
+    M=0
+    @16
+    D=A
+    @EXT_BIT
+    M=D
+    (EXT_DIV_LOOP)
+    // Shift the top bit of n into r, and q along with it
+    @EXT_Q
+    D=M
+    M=D+M
+    @EXT_R
+    D=M
+    M=D+M
+    @EXT_N
+    D=M
+    M=D+M
+    @EXT_DIV_NO_BIT
+    D;JGE
+    @EXT_R
+    M=M+1
+    (EXT_DIV_NO_BIT)
+    // r >= d, where r is unsigned and d is at most 32767
+    @EXT_R
+    D=M
+    @EXT_DIV_SUBTRACT
+    D;JLT
+    @EXT_D
+    D=D-M
+    @EXT_DIV_NEXT
+    D;JLT
+    (EXT_DIV_SUBTRACT)
+    @EXT_D
+    D=M
+    @EXT_R
+    M=M-D
+    @EXT_Q
+    M=M+1
+    (EXT_DIV_NEXT)
+    @EXT_BIT
+    MD=M-1
+    @EXT_DIV_LOOP
+    D;JGT
+    // The remainder has the sign of x, the quotient is negative if the signs differ
+    @EXT_X
+    D=M
+    @EXT_DIV_X_SIGN
+    D;JGE
+    @EXT_R
+    M=-M
+    @EXT_Q
+    M=-M
+    (EXT_DIV_X_SIGN)
+    @EXT_Y
+    D=M
+    @EXT_DIV_RESULT
+    D;JGE
+    @EXT_Q
+    M=-M
+    (EXT_DIV_RESULT)
+    @R14
+    D=M
+    @EXT_DIV_REMAINDER
+    D;JNE
+    @EXT_Q
+    D=M
+    @EXT_RETURN
+    0;JMP
+    (EXT_DIV_REMAINDER)
+    @EXT_R
+    D=M
+    @EXT_RETURN
+    0;JMP
+    (EXT_DIV_BY_MIN)
+    @EXT_X
+    D=M
+    @32767
+    D=D+A
+    D=D+1
+    @EXT_DIV_RESULT
+    D;JNE
+    @EXT_Q
+    M=1
+    @EXT_R
+    M=0
+    @EXT_DIV_RESULT
+    0;JMP
+"};
+
+/// x<<y, doubling x y times.
+const SHL: &str = indoc! {"
+    // shl: x<<y
+    (EXT_SHL)
+    @R15
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @R14
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @R13
+    M=D
+    @R14
+    D=M
+    @EXT_SHIFT_ZERO
+    D;JLT
+    @16
+    D=D-A
+    @EXT_SHIFT_ZERO
+    D;JGE
+    (EXT_SHL_LOOP)
+    @R14
+    MD=M-1
+    @EXT_SHL_DONE
+    D;JLT
+    @R13
+    D=M
+    M=D+M
+    @EXT_SHL_LOOP
+    0;JMP
+    (EXT_SHL_DONE)
+    @R13
+    D=M
+    @EXT_RETURN
+    0;JMP
+"};
+
+/// x>>y, shifting in zeroes.
+///
+/// Hack can't shift right, so each bit of x from bit y up is copied to the
+/// bit y places below it.
+const SHR: &str = indoc! {"
+    // shr: x>>y
+    (EXT_SHR)
+    @R15
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @R14
+    M=D
+    @SP
+    AM=M-1
+    D=M
+    @R13
+    M=D
+    @R14
+    D=M
+    @EXT_SHIFT_ZERO
+    D;JLT
+    @16
+    D=D-A
+    @EXT_SHIFT_ZERO
+    D;JGE
+    @EXT_R
+    M=0
+    @EXT_BIT
+    M=1
+    @EXT_Y
+    M=1
+    (EXT_SHR_FIRST_BIT)
+    @R14
+    MD=M-1
+    @EXT_SHR_LOOP
+    D;JLT
+    @EXT_Y
+    D=M
+    M=D+M
+    @EXT_SHR_FIRST_BIT
+    0;JMP
+    (EXT_SHR_LOOP)
+    @EXT_Y
+    D=M
+    @EXT_SHR_DONE
+    D;JEQ
+    @R13
+    D=D&M
+    @EXT_SHR_SKIP
+    D;JEQ
+    @EXT_BIT
+    D=M
+    @EXT_R
+    M=D+M
+    (EXT_SHR_SKIP)
+    @EXT_Y
+    D=M
+    M=D+M
+    @EXT_BIT
+    D=M
+    M=D+M
+    @EXT_SHR_LOOP
+    0;JMP
+    (EXT_SHR_DONE)
+    @EXT_R
+    D=M
+    @EXT_RETURN
+    0;JMP
+"};
+
+/// Shifting by anything outside 0..=15 gives 0.
+const SHIFT_ZERO: &str = indoc! {"
+    (EXT_SHIFT_ZERO)
+    D=0
+    @EXT_RETURN
+    0;JMP
+"};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::{self, Outcome, RunOptions, Stop};
+    use crate::hack_backend::HackBackend;
+
+    const SCREEN: usize = 16384;
+
+    /// Translate and run a Main class with the OS linked in, optimizing for size.
+    fn run_with_os(main: &str, intrinsics: bool, stack_limit: Option<u16>) -> Outcome {
+        let mut sources = vec![(String::from("Main"), String::from(main))];
+        let linked = crate::os::link(&mut sources, Some("Sys.init"));
+        let intrinsics = if intrinsics { linked } else { Vec::new() };
+        let mut backend = HackBackend::new(OptimizeFor::Size, false, intrinsics, stack_limit);
+        let sources: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(filename, contents)| (filename.as_str(), contents.as_str()))
+            .collect();
+        crate::translate_sources(&mut backend, &sources, Some("Sys.init"));
+        let options = RunOptions {
+            max_steps: 10_000_000,
+            sanitize: stack_limit.is_none(),
+            ram: Vec::new(),
+            show: Vec::new(),
         };
-        self.jmp_counter += 1;
-        common_string
+        let outcome = emulator::emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.problems, 0);
+        outcome
+    }
+
+    /// Run Sys.init with the stack checked against a limit, returning RAM.
+    fn run_checked(code: &str, tail_calls: bool, stack_limit: u16) -> Vec<u16> {
+        let mut backend =
+            HackBackend::new(OptimizeFor::Speed, false, Vec::new(), Some(stack_limit));
+        crate::translate_sources_with(&mut backend, &[("Sys", code)], Some("Sys.init"), tail_calls);
+        let options = RunOptions {
+            max_steps: 10_000_000,
+            sanitize: false,
+            ram: Vec::new(),
+            show: Vec::new(),
+        };
+        let outcome = emulator::emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        outcome.ram
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        // Counts up to 3000 recursively, which is far more frames than fit below the heap
+        let code = "function Sys.init 0\npush constant 0\npush constant 3000\n\
+                    call Sys.count 2\npop temp 0\nlabel END\ngoto END\n\
+                    function Sys.count 0\npush argument 1\nif-goto MORE\npush argument 0\n\
+                    return\nlabel MORE\npush argument 0\npush constant 1\nadd\n\
+                    push argument 1\npush constant 1\nsub\ncall Sys.count 2\nreturn\n";
+        let ram = run_checked(code, true, 2048);
+        assert_eq!(ram[TRAP_CODE as usize], 0);
+        assert_eq!(ram[5], 3000);
+        assert_eq!(ram[0], 261);
+        let ram = run_checked(code, false, 2048);
+        assert_eq!(ram[TRAP_CODE as usize], STACK_OVERFLOW);
     }
-}
 
-/// Append the opcodes to increment the stack pointer to the command input.
-fn increment_stack_pointer(command: &String) -> String {
-    let to_append = formatdoc!(
-        "
-    
-        @SP
-        M=M+1
-
-        "
-    );
-    formatdoc!("{}{}", command, to_append)
+    #[test]
+    fn tail_calls_check_the_stack() {
+        // Sys.init's call to Sys.f leaves SP at 266, and the tail call copies 5 words above the argument
+        let code = "function Sys.init 0\ncall Sys.f 0\npop temp 0\nlabel END\ngoto END\n\
+                    function Sys.f 0\npush constant 1\ncall Sys.id 1\nreturn\n\
+                    function Sys.id 0\npush argument 0\nreturn\n";
+        assert_eq!(run_checked(code, true, 300)[TRAP_CODE as usize], 0);
+        assert_eq!(
+            run_checked(code, true, 270)[TRAP_CODE as usize],
+            STACK_OVERFLOW
+        );
+    }
+
+    #[test]
+    fn asm_blocks_pass_through() {
+        let code =
+            "function Main.f 0\nasm\n  @SP\n  M=M+1\n(LOOP)\n@LOOP\nD;JGT\n@R13\nendasm\nreturn\n";
+        let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+        crate::translate_sources(&mut backend, &[("Main", code)], None);
+        let asm = crate::asm::serialize(&crate::listing::items(backend.chunks()));
+        // Only the block's own label is scoped to the function
+        assert!(asm.contains(
+            "(Main.f)\n\n// asm\n@SP\nM=M+1\n(Main.f$LOOP)\n@Main.f$LOOP\nD;JGT\n@R13\n\n// return\n"
+        ));
+    }
+
+    #[test]
+    fn wide_constants_push_their_16_bit_value() {
+        let translate = |code: &str| {
+            let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+            crate::translate_sources(&mut backend, &[("Main", code)], None);
+            crate::asm::serialize(&crate::listing::items(backend.chunks()))
+        };
+        let minus_one = translate("push constant -1\n");
+        assert!(minus_one.contains("// push constant -1\n@SP\nAM=M+1\nA=A-1\nM=-1\n"));
+        assert_eq!(translate("push constant 0xFFFF\n"), minus_one);
+        assert_eq!(translate("push constant 65535\n"), minus_one);
+        let min = translate("push constant -32768\n");
+        assert!(min.contains("// push constant -32768\n@32767\nD=!A\n"));
+        assert_eq!(translate("push constant 0x8000\n"), min);
+    }
+
+    #[test]
+    fn locals_start_at_zero() {
+        // Sys.dirty leaves nonzero values where the next function's locals go, and
+        // Sys.N returns N plus the sum of its N locals
+        let mut code = String::from(
+            "function Sys.init 0\ncall Sys.dirty 0\npop temp 0\n\
+             call Sys.1 0\npop temp 1\ncall Sys.dirty 0\npop temp 0\n\
+             call Sys.2 0\npop temp 2\ncall Sys.dirty 0\npop temp 0\n\
+             call Sys.3 0\npop temp 3\ncall Sys.dirty 0\npop temp 0\n\
+             call Sys.7 0\npop temp 4\nlabel END\ngoto END\n\
+             function Sys.dirty 0\n",
+        );
+        code.push_str(&"push constant 99\n".repeat(8));
+        code.push_str("return\n");
+        for n_vars in [1, 2, 3, 7] {
+            code.push_str(&format!(
+                "function Sys.{n_vars} {n_vars}\npush constant {n_vars}\n"
+            ));
+            for i in 0..n_vars {
+                code.push_str(&format!("push local {i}\nadd\n"));
+            }
+            code.push_str("return\n");
+        }
+        let results: Vec<Vec<u16>> = [OptimizeFor::Speed, OptimizeFor::Size]
+            .into_iter()
+            .map(|optimize| {
+                let mut backend = HackBackend::new(optimize, false, Vec::new(), None);
+                crate::translate_sources(&mut backend, &[("Sys", &code)], Some("Sys.init"));
+                let options = RunOptions {
+                    max_steps: 100_000,
+                    sanitize: false,
+                    ram: Vec::new(),
+                    show: Vec::new(),
+                };
+                let outcome = emulator::emulate(backend.chunks(), &options);
+                assert_eq!(outcome.stop, Stop::Halted);
+                outcome.ram[0..10].to_vec()
+            })
+            .collect();
+        assert_eq!(results[0][0], 261);
+        assert_eq!(results[0][6..10], [1, 2, 3, 7]);
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn failed_stack_check_leaves_its_code_past_the_heap() {
+        let main = "function Main.main 0\npush constant 1\ncall Main.main 1\nreturn\n";
+        let outcome = run_with_os(main, false, Some(2048));
+        assert_eq!(outcome.ram[TRAP_CODE as usize], STACK_OVERFLOW);
+        assert_ne!(outcome.ram[TRAP_PC as usize], 0);
+    }
+
+    #[test]
+    fn draw_pixel_intrinsic_draws_in_the_pen_color() {
+        let main = "function Main.main 0\n\
+                    push constant 1\npush constant 2\ncall Screen.drawPixel 2\npop temp 0\n\
+                    push constant 17\npush constant 2\ncall Screen.drawPixel 2\npop temp 0\n\
+                    push constant 0\ncall Screen.setColor 1\npop temp 0\n\
+                    push constant 17\npush constant 2\ncall Screen.drawPixel 2\npop temp 0\n\
+                    push constant 0\nreturn\n";
+        let screen = run_with_os(main, true, None).ram[SCREEN..].to_vec();
+        // Row 2 starts 64 words in, and x 1 and 17 are bit 1 of its first two words
+        assert_eq!(screen[64], 2);
+        assert_eq!(screen[65], 0);
+        assert_eq!(screen, run_with_os(main, false, None).ram[SCREEN..]);
+    }
 }
@@ -0,0 +1,651 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::{Address, AsmItem};
+
+/// Code for pushing D, shared by every push and call template.
+const PUSH_D: &str = "@SP\nA=M\nM=D\n@SP\nM=M+1";
+
+/// Code for popping into D.
+const POP_D: &str = "@SP\nAM=M-1\nD=M";
+
+/// The inline intrinsics for `Memory.peek` and `Memory.poke`.
+const PEEK: &str = "@SP\nA=M-1\nA=M\nD=M\n@SP\nA=M-1\nM=D";
+const POKE: &str = "@SP\nAM=M-1\nD=M\n@SP\nA=M-1\nA=M\nM=D\n@SP\nA=M-1\nM=0";
+/// The code the `Screen.setColor` intrinsic runs before the call.
+const SET_COLOR: &str = "@SP\nA=M-1\nD=-M\nD=D|M\nD=!D\n@INTRINSIC_COLOR\nM=D";
+
+/// Code for copying a word from RAM[R13] to RAM[R14] in a tail call.
+const COPY: &str = "@R13\nA=M\nD=M\n@R14\nA=M\nM=D\n@R13\nM=M+1\n@R14\nM=M+1";
+
+/// The VM commands recovered for one source file.
+pub struct VmFile {
+    pub name: String,
+    pub commands: Vec<String>,
+}
+
+/// Reads through assembly items, matching them against template text.
+#[derive(Clone)]
+struct Cursor<'a> {
+    items: &'a [AsmItem],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self) -> Option<&'a AsmItem> {
+        let item = self.items.get(self.position)?;
+        self.position += 1;
+        Some(item)
+    }
+
+    /// Match lines of assembly exactly.
+    fn lines(&mut self, text: &str) -> Option<()> {
+        for line in text.lines() {
+            if self.next()?.to_string() != line {
+                return None;
+            }
+        }
+        Some(())
+    }
+
+    /// Match an A-instruction loading a number.
+    fn value(&mut self) -> Option<u16> {
+        match self.next()? {
+            AsmItem::AInstr(Address::Value(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Match an A-instruction loading a symbol.
+    fn symbol(&mut self) -> Option<&'a str> {
+        match self.next()? {
+            AsmItem::AInstr(Address::Symbol(symbol)) => Some(symbol.as_str()),
+            _ => None,
+        }
+    }
+
+    fn label(&mut self) -> Option<&'a str> {
+        match self.next()? {
+            AsmItem::Label(label) => Some(label.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// Tracks which function the decompiled commands belong to.
+struct Scope {
+    file: String,
+    function: String,
+}
+
+impl Scope {
+    /// Turn a scoped assembly label back into the VM label.
+    fn vm_label<'b>(&self, label: &'b str) -> Option<&'b str> {
+        let (scope, label) = label.split_once('$')?;
+        if scope == format!("{}.{}", self.file, self.function) {
+            Some(label)
+        } else {
+            None
+        }
+    }
+
+    /// Turn a `File.index` symbol from this file back into a static index.
+    fn static_index(&self, symbol: &str) -> Option<u16> {
+        let (file, index) = symbol.rsplit_once('.')?;
+        if file == self.file {
+            index.parse().ok()
+        } else {
+            None
+        }
+    }
+}
+
+type Template = fn(&mut Cursor<'_>, &Scope) -> Option<Vec<String>>;
+
+/// Every template CodeWriter emits, longest first where one starts like another.
+const TEMPLATES: [Template; 22] = [
+    bootstrap,
+    infinite_loop,
+    subroutines,
+    stack_check,
+    intrinsic,
+    extended,
+    call,
+    call_shared,
+    tail_call,
+    function,
+    return_,
+    return_shared,
+    compare,
+    binary,
+    unary,
+    push,
+    push_small_constant,
+    pop,
+    pop_segment,
+    label,
+    goto,
+    if_goto,
+];
+
+/// Recover VM files from assembly generated by this translator.
+///
+/// Comments are ignored, so this works on stripped assembly too. Regions that
+/// don't match any template are flagged with a comment in the output and
+/// reported on stderr.
+pub fn decompile(items: &[AsmItem], default_file: &str) -> Vec<VmFile> {
+    let items: Vec<AsmItem> = items
+        .iter()
+        .filter(|item| !matches!(item, AsmItem::Comment(_)))
+        .cloned()
+        .collect();
+    let mut files: Vec<VmFile> = Vec::new();
+    let mut scope = Scope {
+        file: String::from(default_file),
+        function: String::new(),
+    };
+    let mut cursor = Cursor {
+        items: &items,
+        position: 0,
+    };
+    let mut unrecognized: Vec<&AsmItem> = Vec::new();
+    let mut rom_address = 0;
+    let mut unrecognized_start = 0;
+
+    while cursor.position < items.len() || !unrecognized.is_empty() {
+        let start = cursor.position;
+        let matched = TEMPLATES.iter().find_map(|template| {
+            let mut attempt = cursor.clone();
+            template(&mut attempt, &scope).map(|commands| (attempt, commands))
+        });
+        if matched.is_none() && start < items.len() {
+            if unrecognized.is_empty() {
+                unrecognized_start = rom_address;
+            }
+            let item = cursor.next().unwrap();
+            if !matches!(item, AsmItem::Label(_)) {
+                rom_address += 1;
+            }
+            unrecognized.push(item);
+            continue;
+        }
+
+        if !unrecognized.is_empty() {
+            let region = format!(
+                "ROM {}..{} matches no template",
+                unrecognized_start,
+                rom_address - 1
+            );
+            eprintln!("{}: {}", scope.file, region);
+            let commands = vm_file(&mut files, &scope.file);
+            commands.push(format!("// {region}:"));
+            for item in unrecognized.drain(..) {
+                commands.push(format!("//   {item}"));
+            }
+        }
+        let Some((next, mut commands)) = matched else {
+            break;
+        };
+        rom_address += items[start..next.position]
+            .iter()
+            .filter(|item| !matches!(item, AsmItem::Label(_)))
+            .count();
+        cursor = next;
+
+        if let Some(name) = commands
+            .first()
+            .and_then(|command| command.strip_prefix("function "))
+        {
+            let name = name.split(' ').next().unwrap();
+            let (file, function) = name.split_once('.').unwrap_or((default_file, name));
+            scope.file = String::from(file);
+            scope.function = String::from(function);
+        }
+        vm_file(&mut files, &scope.file).append(&mut commands);
+    }
+    files.retain(|file| !file.commands.is_empty());
+    files
+}
+
+/// The commands of the named file, adding it if it's new.
+fn vm_file<'b>(files: &'b mut Vec<VmFile>, name: &str) -> &'b mut Vec<String> {
+    let index = match files.iter().position(|file| file.name == name) {
+        Some(index) => index,
+        None => {
+            files.push(VmFile {
+                name: String::from(name),
+                commands: Vec::new(),
+            });
+            files.len() - 1
+        }
+    };
+    &mut files[index].commands
+}
+
+/// Setting SP and calling the entry point, which isn't part of any file.
+fn bootstrap(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@256\nD=A\n@SP\nM=D")?;
+    let calls: [Template; 2] = [call, call_shared];
+    *cursor = calls.iter().find_map(|template| {
+        let mut attempt = cursor.clone();
+        template(&mut attempt, scope).map(|_| attempt)
+    })?;
+    Some(Vec::new())
+}
+
+fn infinite_loop(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("(INFINITE_LOOP)\n@INFINITE_LOOP\n0;JMP")?;
+    Some(Vec::new())
+}
+
+/// The shared subroutines for extended arithmetic, which aren't part of any file.
+fn subroutines(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@EXT_END\n0;JMP")?;
+    while cursor.next()?.to_string() != "(EXT_END)" {}
+    Some(Vec::new())
+}
+
+/// A stack check from `--checked`, which isn't a command of its own.
+fn stack_check(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    // Pop checks first say how many values they need
+    let mut words = cursor.clone();
+    if words.value().is_some() && words.lines("D=A\n@R14\nM=D").is_some() {
+        *cursor = words;
+    }
+    let return_label = cursor.symbol()?;
+    cursor.lines("D=A")?;
+    if !matches!(
+        cursor.symbol()?,
+        "CHECK_PUSH" | "CHECK_CALL" | "CHECK_POP" | "CHECK_POP_FRAME"
+    ) {
+        return None;
+    }
+    cursor.lines("0;JMP")?;
+    if cursor.label()? != return_label {
+        return None;
+    }
+    Some(Vec::new())
+}
+
+/// A call replaced by an intrinsic, see CodeWriter::write_call.
+///
+/// Multiplying and dividing share the extended arithmetic subroutines, but
+/// come back to a call's return label rather than an `EXT_RET_` one. The
+/// code before a `Screen.setColor` call gives no command of its own, as the
+/// call follows.
+fn intrinsic(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    if cursor.clone().lines(SET_COLOR).is_some() {
+        cursor.position += SET_COLOR.lines().count();
+        return Some(Vec::new());
+    }
+    if cursor.clone().lines(PEEK).is_some() {
+        cursor.position += PEEK.lines().count();
+        return Some(vec![String::from("call Memory.peek 1")]);
+    }
+    if cursor.clone().lines(POKE).is_some() {
+        cursor.position += POKE.lines().count();
+        return Some(vec![String::from("call Memory.poke 2")]);
+    }
+    let return_label = cursor.symbol()?;
+    cursor.lines("D=A")?;
+    let function_name = match cursor.symbol()? {
+        "EXT_MUL" => "Math.multiply",
+        "EXT_DIV" => "Math.divide",
+        "INTRINSIC_DRAW_PIXEL" => "Screen.drawPixel",
+        _ => return None,
+    };
+    cursor.lines("0;JMP")?;
+    if cursor.label()? != return_label
+        || !return_label.starts_with(format!("{function_name}$ret.").as_str())
+    {
+        return None;
+    }
+    Some(vec![format!("call {function_name} 2")])
+}
+
+/// An extended arithmetic command, which calls one of the shared subroutines.
+fn extended(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    let return_label = cursor.symbol()?;
+    cursor.lines("D=A")?;
+    let op = match cursor.symbol()? {
+        "EXT_MUL" => "mul",
+        "EXT_DIV" => "div",
+        "EXT_MOD" => "mod",
+        "EXT_SHL" => "shl",
+        "EXT_SHR" => "shr",
+        _ => return None,
+    };
+    cursor.lines("0;JMP")?;
+    if cursor.label()? != return_label {
+        return None;
+    }
+    Some(vec![String::from(op)])
+}
+
+fn call(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    let return_label = cursor.symbol()?;
+    cursor.lines("D=A")?;
+    cursor.lines(PUSH_D)?;
+    for segment in ["LCL", "ARG", "THIS", "THAT"] {
+        cursor.lines(format!("@{segment}\nD=M").as_str())?;
+        cursor.lines(PUSH_D)?;
+    }
+    cursor.lines("@5\nD=A\n@SP\nD=M-D")?;
+    let n_args = cursor.value()?;
+    cursor.lines("D=D-A\n@ARG\nM=D\n@SP\nD=M\n@LCL\nM=D")?;
+    let function_name = cursor.symbol()?;
+    cursor.lines("0;JMP")?;
+    if cursor.label()? != return_label
+        || !return_label.starts_with(format!("{function_name}$ret.").as_str())
+    {
+        return None;
+    }
+    Some(vec![format!("call {function_name} {n_args}")])
+}
+
+/// A call made through the shared VM_CALL, when optimizing for size.
+fn call_shared(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    let n_args = cursor.value()?;
+    cursor.lines("D=A\n@R13\nM=D")?;
+    let function_name = cursor.symbol()?;
+    cursor.lines("D=A\n@R14\nM=D")?;
+    let return_label = cursor.symbol()?;
+    cursor.lines("D=A\n@VM_CALL\n0;JMP")?;
+    if cursor.label()? != return_label
+        || !return_label.starts_with(format!("{function_name}$ret.").as_str())
+    {
+        return None;
+    }
+    Some(vec![format!("call {function_name} {n_args}")])
+}
+
+fn tail_call(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@LCL\nD=M\n@5\nD=D-A\n@R13\nM=D\n@SP\nD=M\n@R14\nM=D")?;
+    for _ in 0..5 {
+        cursor.lines(COPY)?;
+    }
+    cursor.lines("@SP\nD=M")?;
+    let n_args = cursor.value()?;
+    cursor.lines("D=D-A\n@R13\nM=D\n@ARG\nD=M\n@R14\nM=D")?;
+    for _ in 0..n_args + 5 {
+        cursor.lines(COPY)?;
+    }
+    cursor.lines("@R14\nD=M\n@LCL\nM=D\n@SP\nM=D")?;
+    let function_name = cursor.symbol()?;
+    cursor.lines("0;JMP")?;
+    Some(vec![
+        format!("call {function_name} {n_args}"),
+        String::from("return"),
+    ])
+}
+
+fn function(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    let function_name = cursor.label()?;
+    if function_name.contains('$') || !function_name.contains('.') {
+        return None;
+    }
+    // Locals are zeroed one of three ways, see CodeWriter::generate_init_locals.
+    // A `push constant 0` straight after looks just like one more local, which
+    // does the same thing.
+    let n_vars = init_locals_speed(cursor.clone())
+        .or_else(|| init_locals_size(cursor.clone(), function_name))
+        .map(|(n_vars, rest)| {
+            *cursor = rest;
+            n_vars
+        })
+        .unwrap_or_else(|| {
+            let mut n_vars = 0;
+            while cursor.clone().lines("@SP\nAM=M+1\nA=A-1\nM=0").is_some() {
+                cursor.position += 4;
+                n_vars += 1;
+            }
+            n_vars
+        });
+    Some(vec![format!("function {function_name} {n_vars}")])
+}
+
+/// Locals zeroed at offsets from SP, with SP bumped once at the end.
+fn init_locals_speed(mut cursor: Cursor<'_>) -> Option<(u16, Cursor<'_>)> {
+    cursor.lines("@SP\nA=M\nM=0")?;
+    let mut n_vars = 1;
+    while cursor.clone().lines("A=A+1\nM=0").is_some() {
+        cursor.position += 2;
+        n_vars += 1;
+    }
+    if cursor.value()? != n_vars {
+        return None;
+    }
+    cursor.lines("D=A\n@SP\nM=D+M")?;
+    Some((n_vars, cursor))
+}
+
+/// Locals zeroed by a loop counting down in D.
+fn init_locals_size<'a>(mut cursor: Cursor<'a>, function_name: &str) -> Option<(u16, Cursor<'a>)> {
+    let n_vars = cursor.value()?;
+    let loop_label = format!("{function_name}$INIT_LOCALS");
+    cursor.lines(
+        format!("D=A\n({loop_label})\n@SP\nAM=M+1\nA=A-1\nM=0\nD=D-1\n@{loop_label}\nD;JGT")
+            .as_str(),
+    )?;
+    Some((n_vars, cursor))
+}
+
+fn return_(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@LCL\nD=M\n@FRAME\nM=D\n@5\nD=D-A\n@RET\nAM=D\nD=M\n@RET\nM=D")?;
+    cursor.lines(POP_D)?;
+    cursor.lines("@ARG\nA=M\nM=D\n@ARG\nD=M\nD=D+1\n@SP\nM=D")?;
+    cursor.lines("@FRAME\nD=M\nA=M-1\nD=M\n@THAT\nM=D")?;
+    for (segment, offset) in [("THIS", 2), ("ARG", 3), ("LCL", 4)] {
+        cursor
+            .lines(format!("@{offset}\nD=A\n@FRAME\nD=M-D\nA=D\nD=M\n@{segment}\nM=D").as_str())?;
+    }
+    cursor.lines("@RET\nA=M\n0;JMP")?;
+    Some(vec![String::from("return")])
+}
+
+/// A return through the shared VM_RETURN, when optimizing for size.
+fn return_shared(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@VM_RETURN\n0;JMP")?;
+    Some(vec![String::from("return")])
+}
+
+fn compare(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines(POP_D)?;
+    cursor.lines("@SP\nAM=M-1\nD=M-D")?;
+    let true_label = cursor.symbol()?;
+    let op = match cursor.next()?.to_string().as_str() {
+        "D;JEQ" => "eq",
+        "D;JGT" => "gt",
+        "D;JLT" => "lt",
+        _ => return None,
+    };
+    cursor.lines("@SP\nA=M\nM=0")?;
+    let false_label = cursor.symbol()?;
+    cursor.lines("0;JMP")?;
+    cursor.lines(format!("({true_label})\n@SP\nA=M\nM=-1\n({false_label})").as_str())?;
+    cursor.lines("@SP\nM=M+1")?;
+    Some(vec![String::from(op)])
+}
+
+fn binary(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines(POP_D)?;
+    cursor.lines("@SP\nAM=M-1")?;
+    let op = match cursor.next()?.to_string().as_str() {
+        "M=D+M" => "add",
+        "M=M-D" => "sub",
+        "M=D&M" => "and",
+        "M=D|M" => "or",
+        _ => return None,
+    };
+    cursor.lines("@SP\nM=M+1")?;
+    Some(vec![String::from(op)])
+}
+
+fn unary(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines(POP_D)?;
+    let op = match cursor.next()?.to_string().as_str() {
+        "M=-D" => "neg",
+        "M=!D" => "not",
+        _ => return None,
+    };
+    cursor.lines("@SP\nM=M+1")?;
+    Some(vec![String::from(op)])
+}
+
+/// The segment and index a push or pop accesses directly through one A-instruction.
+fn direct_segment(item: &AsmItem, scope: &Scope) -> Option<(&'static str, u16)> {
+    match item {
+        AsmItem::AInstr(Address::Value(value)) if (5..=12).contains(value) => {
+            Some(("temp", value - 5))
+        }
+        AsmItem::AInstr(Address::Symbol(symbol)) => match symbol.as_str() {
+            "THIS" => Some(("pointer", 0)),
+            "THAT" => Some(("pointer", 1)),
+            _ => Some(("static", scope.static_index(symbol)?)),
+        },
+        _ => None,
+    }
+}
+
+/// The segment whose base address is in a register.
+fn based_segment(symbol: &str) -> Option<&'static str> {
+    match symbol {
+        "LCL" => Some("local"),
+        "ARG" => Some("argument"),
+        "THIS" => Some("this"),
+        "THAT" => Some("that"),
+        _ => None,
+    }
+}
+
+fn push(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    let item = cursor.next()?;
+    let command = match (item, cursor.next()?.to_string().as_str()) {
+        (AsmItem::AInstr(Address::Value(value)), "D=A") => format!("push constant {value}"),
+        (AsmItem::AInstr(Address::Value(value)), "D=-A") => {
+            format!("push constant {}", -(*value as i16))
+        }
+        (AsmItem::AInstr(Address::Value(value)), "D=!A") => {
+            format!("push constant {}", !(*value as i16))
+        }
+        (AsmItem::AInstr(Address::Symbol(symbol)), "D=M") if based_segment(symbol).is_some() => {
+            let mut indexed = cursor.clone();
+            let index = indexed.value();
+            if let (Some(index), Some(())) = (index, indexed.lines("A=D+A\nD=M")) {
+                *cursor = indexed;
+                format!("push {} {index}", based_segment(symbol).unwrap())
+            } else {
+                let (segment, index) = direct_segment(item, scope)?;
+                format!("push {segment} {index}")
+            }
+        }
+        (_, "D=M") => {
+            let (segment, index) = direct_segment(item, scope)?;
+            format!("push {segment} {index}")
+        }
+        _ => return None,
+    };
+    cursor.lines(PUSH_D)?;
+    Some(vec![command])
+}
+
+/// 0, 1 and -1 are written straight to the stack.
+fn push_small_constant(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines("@SP\nAM=M+1\nA=A-1")?;
+    let value = match cursor.next()?.to_string().as_str() {
+        "M=0" => 0,
+        "M=1" => 1,
+        "M=-1" => -1,
+        _ => return None,
+    };
+    Some(vec![format!("push constant {value}")])
+}
+
+fn pop(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines(POP_D)?;
+    let (segment, index) = direct_segment(cursor.next()?, scope)?;
+    cursor.lines("M=D")?;
+    Some(vec![format!("pop {segment} {index}")])
+}
+
+fn pop_segment(cursor: &mut Cursor<'_>, _scope: &Scope) -> Option<Vec<String>> {
+    let segment = based_segment(cursor.symbol()?)?;
+    cursor.lines("D=M")?;
+    let index = cursor.value()?;
+    cursor.lines("AD=D+A\n@R13\nM=D")?;
+    cursor.lines(POP_D)?;
+    cursor.lines("@R13\nA=M\nM=D")?;
+    Some(vec![format!("pop {segment} {index}")])
+}
+
+fn label(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    let label = scope.vm_label(cursor.label()?)?;
+    Some(vec![format!("label {label}")])
+}
+
+fn goto(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    let label = scope.vm_label(cursor.symbol()?)?;
+    cursor.lines("0;JMP")?;
+    Some(vec![format!("goto {label}")])
+}
+
+fn if_goto(cursor: &mut Cursor<'_>, scope: &Scope) -> Option<Vec<String>> {
+    cursor.lines(POP_D)?;
+    let label = scope.vm_label(cursor.symbol()?)?;
+    cursor.lines("D;JNE")?;
+    Some(vec![format!("if-goto {label}")])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::code_writer::OptimizeFor;
+    use crate::hack_backend::HackBackend;
+
+    const MAIN: &str = "function Main.main 1\npush constant 5\npop local 0\npush local 0\n\
+                        push static 0\nlt\nif-goto SMALL\npush argument 0\ncall Main.double 1\n\
+                        pop static 0\nlabel SMALL\npush constant 0\nreturn\n\
+                        function Main.double 0\npush argument 0\npush argument 0\nadd\nreturn\n";
+    const SYS: &str = "function Sys.init 0\npush constant 3\ncall Main.main 1\npop temp 0\n\
+                       label END\ngoto END\n";
+
+    /// Translate then decompile, returning the commands recovered for each file.
+    fn round_trip(sources: &[(&str, &str)], optimize: OptimizeFor) -> Vec<(String, String)> {
+        let mut backend = HackBackend::new(optimize, false, Vec::new(), None);
+        crate::translate_sources(&mut backend, sources, Some("Sys.init"));
+        // Through the text, as decompiling a .asm file does
+        let text = asm::serialize(&crate::listing::items(backend.chunks()));
+        let mut files: Vec<(String, String)> = decompile(&asm::parse(&text), "Main")
+            .into_iter()
+            .map(|file| (file.name, file.commands.join("\n") + "\n"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn decompiles_what_was_translated() {
+        let expected = vec![
+            (String::from("Main"), String::from(MAIN)),
+            (String::from("Sys"), String::from(SYS)),
+        ];
+        for optimize in [OptimizeFor::Speed, OptimizeFor::Size] {
+            let files = round_trip(&[("Sys", SYS), ("Main", MAIN)], optimize);
+            assert_eq!(files, expected, "{optimize:?}");
+        }
+    }
+
+    #[test]
+    fn decompiles_extended_ops() {
+        for optimize in [OptimizeFor::Speed, OptimizeFor::Size] {
+            let files = round_trip(&[("Sys", crate::tests::EXTENDED_OPS)], optimize);
+            assert_eq!(
+                files,
+                [(
+                    String::from("Sys"),
+                    String::from(crate::tests::EXTENDED_OPS)
+                )]
+            );
+        }
+    }
+}
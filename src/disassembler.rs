@@ -0,0 +1,138 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::{self, Address, AsmItem, Comp, Dest, Jump};
+use crate::assembler;
+use std::collections::BTreeSet;
+
+/// Predefined symbols worth recovering when an address is used to access RAM.
+///
+/// R0-R4 are left out since SP-THAT name the same registers, and the temp
+/// registers R5-R12 are clearer as plain numbers.
+const RAM_SYMBOLS: [(&str, u16); 10] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// Read the words of a .hack file, one 16 character binary string per line.
+pub fn read_hack(text: &str) -> Vec<u16> {
+    text.lines()
+        .enumerate()
+        .map(|(number, line)| (number, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(number, line)| {
+            if line.len() != 16 {
+                panic!("Line {} isn't a 16-bit word: {:?}", number + 1, line);
+            }
+            u16::from_str_radix(line, 2)
+                .unwrap_or_else(|_| panic!("Line {} isn't binary: {:?}", number + 1, line))
+        })
+        .collect()
+}
+
+/// Decode one Hack word, with A-instructions left as plain numbers.
+///
+/// Returns None if the word isn't something the Hack assembler could produce.
+pub fn decode(word: u16) -> Option<AsmItem> {
+    if word & 0x8000 == 0 {
+        return Some(AsmItem::AInstr(Address::Value(word)));
+    }
+    if word & 0x6000 != 0x6000 {
+        return None;
+    }
+    Some(AsmItem::CInstr {
+        dest: Dest::from_bits(word >> 3 & 0b111)?,
+        comp: Comp::from_bits(word >> 6 & 0b1111111)?,
+        jump: Jump::from_bits(word & 0b111)?,
+    })
+}
+
+/// Turn Hack machine code back into assembly.
+///
+/// A-instructions feeding a jump become synthesized `L<address>` labels, and
+/// ones feeding a RAM access get their predefined symbol back, if they have one.
+/// Panics if a word can't be decoded or the result doesn't assemble back into
+/// the same machine code.
+pub fn disassemble(words: &[u16]) -> Vec<AsmItem> {
+    let mut instructions: Vec<AsmItem> = words
+        .iter()
+        .enumerate()
+        .map(|(rom_address, word)| match decode(*word) {
+            Some(item) => item,
+            None => panic!(
+                "{:016b} at ROM {} isn't a Hack instruction!",
+                word, rom_address
+            ),
+        })
+        .collect();
+
+    let mut targets = BTreeSet::new();
+    for i in 0..instructions.len() {
+        let Some(AsmItem::CInstr { dest, comp, jump }) = instructions.get(i + 1).cloned() else {
+            continue;
+        };
+        let AsmItem::AInstr(Address::Value(value)) = instructions[i] else {
+            continue;
+        };
+        if jump != Jump::Null {
+            // Targets past the end of the program can't be given a label
+            if value as usize <= instructions.len() {
+                targets.insert(value);
+                instructions[i] = AsmItem::symbol(format!("L{value}").as_str());
+            }
+        } else if dest.writes_memory() || comp.reads_memory() {
+            if let Some((symbol, _)) = RAM_SYMBOLS.iter().find(|(_, address)| *address == value) {
+                instructions[i] = AsmItem::symbol(symbol);
+            }
+        }
+    }
+
+    let mut items = Vec::new();
+    for (rom_address, instruction) in instructions.into_iter().enumerate() {
+        if targets.contains(&(rom_address as u16)) {
+            items.push(AsmItem::label(format!("L{rom_address}").as_str()));
+        }
+        items.push(instruction);
+    }
+    if targets.contains(&(words.len() as u16)) {
+        items.push(AsmItem::label(format!("L{}", words.len()).as_str()));
+    }
+
+    let round_trip = assembler::assemble(&asm::parse(&asm::serialize(&items)));
+    if round_trip != words {
+        panic!("Disassembly doesn't assemble back into the same program!");
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn disassembles_into_what_assembles_back() {
+        let source = "@7\nD=A\n(LOOP)\n@SP\nM=M+1\nD=D-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP\n";
+        let words = assemble(&asm::parse(source));
+        let items = disassemble(&words);
+        assert_eq!(assemble(&items), words);
+        // The jump targets get labels, and SP its symbol
+        let text = asm::serialize(&items);
+        assert!(text.contains("(L2)\n@SP\n"));
+        assert!(text.contains("@L2\nD;JGT\n"));
+    }
+
+    #[test]
+    fn words_that_arent_instructions_dont_decode() {
+        assert!(decode(0b1000_0000_0000_0000).is_none());
+        assert_eq!(decode(5), Some(AsmItem::AInstr(Address::Value(5))));
+    }
+}
@@ -0,0 +1,452 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::{AsmItem, Comp, Dest, Jump};
+use crate::assembler::{self, SymbolTable};
+use crate::listing::{Chunk, Origin};
+use std::collections::HashSet;
+
+/// Bottom of the stack, where the bootstrap sets SP.
+const STACK_BASE: u16 = 256;
+/// First address of the static region, right after R15.
+const STATIC_BASE: u16 = 16;
+/// First address of the heap, right after the stack.
+const HEAP_BASE: u16 = 2048;
+/// The screen, followed by the keyboard at the end of RAM.
+const SCREEN: u16 = 16384;
+const KBD: u16 = 24576;
+/// Instructions that fit in ROM.
+const ROM_SIZE: usize = 32768;
+
+/// How to run a translated program with `--run`.
+pub struct RunOptions {
+    /// Stop after this many instructions if the program hasn't halted.
+    pub max_steps: u64,
+    /// Report suspicious memory accesses as the program runs.
+    pub sanitize: bool,
+    /// RAM to set before starting, as address and value.
+    pub ram: Vec<(u16, i16)>,
+    /// RAM addresses to print once the program stops.
+    pub show: Vec<u16>,
+}
+
+/// How a run ended.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    Halted,
+    RanOffEnd,
+    OutOfSteps,
+    /// The program didn't fit in ROM, so it wasn't run.
+    TooBig(usize),
+}
+
+/// Where running a program got to.
+pub struct Outcome {
+    pub stop: Stop,
+    pub steps: u64,
+    pub ram: Vec<u16>,
+    /// How many problems the sanitizer reported.
+    pub problems: usize,
+}
+
+/// A decoded Hack instruction.
+enum Instruction {
+    Load(u16),
+    Compute { dest: Dest, comp: Comp, jump: Jump },
+}
+
+/// Run the program and print how it went and the RAM asked for.
+///
+/// Returns false if it didn't fit in ROM or the sanitizer reported anything.
+pub fn run(chunks: &[Chunk], options: &RunOptions) -> bool {
+    let outcome = emulate(chunks, options);
+    let steps = outcome.steps;
+    match outcome.stop {
+        Stop::Halted => println!("Halted after {steps} steps"),
+        Stop::RanOffEnd => println!("Ran off the end of the program after {steps} steps"),
+        Stop::OutOfSteps => println!("Stopped after {steps} steps without halting"),
+        Stop::TooBig(size) => {
+            println!(
+                "The program is {size} instructions, more than the {ROM_SIZE} that fit in ROM"
+            );
+            return false;
+        }
+    }
+    for address in &options.show {
+        println!("RAM[{address}] = {}", outcome.ram[*address as usize] as i16);
+    }
+    if outcome.problems > 0 {
+        println!("The sanitizer found {} problem(s)", outcome.problems);
+    }
+    outcome.problems == 0
+}
+
+/// Run the program on a Hack CPU until it halts or runs out of steps.
+///
+/// The program counts as halted when it jumps back to the A-instruction
+/// that loaded the jump's own target, like `(END) @END 0;JMP`.
+pub fn emulate(chunks: &[Chunk], options: &RunOptions) -> Outcome {
+    let mut symbols = SymbolTable::new(&crate::listing::items(chunks));
+    let mut rom = Vec::new();
+    // The chunk each instruction came from, and whether it's the chunk's first
+    let mut chunk_of = Vec::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let start = rom.len();
+        for item in &chunk.items {
+            let Some(word) = assembler::encode(item, &mut symbols) else {
+                continue;
+            };
+            rom.push(match item {
+                AsmItem::CInstr { dest, comp, jump } => Instruction::Compute {
+                    dest: *dest,
+                    comp: *comp,
+                    jump: *jump,
+                },
+                _ => Instruction::Load(word),
+            });
+            chunk_of.push((index, rom.len() - 1 == start));
+        }
+    }
+
+    let mut ram = vec![0u16; 32768];
+    if rom.len() > ROM_SIZE {
+        return Outcome {
+            stop: Stop::TooBig(rom.len()),
+            steps: 0,
+            ram,
+            problems: 0,
+        };
+    }
+    for (address, value) in &options.ram {
+        ram[*address as usize] = *value as u16;
+    }
+    let mut sanitizer = options.sanitize.then(|| Sanitizer::new(&symbols, options));
+    let (mut a, mut d, mut pc) = (0u16, 0u16, 0usize);
+    // Whether A holds an address worked out from SP, for the sanitizer
+    let mut a_from_sp = false;
+    let mut steps = 0;
+    let mut stop = Stop::OutOfSteps;
+    while steps < options.max_steps && pc < rom.len() {
+        steps += 1;
+        if let Some(sanitizer) = sanitizer.as_mut() {
+            let (index, first) = chunk_of[pc];
+            sanitizer.enter(&chunks[index], first, pc, &ram);
+        }
+        match rom[pc] {
+            Instruction::Load(value) => {
+                a = value;
+                a_from_sp = false;
+                pc += 1;
+            }
+            Instruction::Compute { dest, comp, jump } => {
+                let address = (a & 0x7FFF) as usize;
+                let m = if comp.reads_memory() {
+                    if let Some(sanitizer) = sanitizer.as_mut() {
+                        sanitizer.read(address as u16, a_from_sp, pc, &ram);
+                    }
+                    ram[address]
+                } else {
+                    0
+                };
+                let out = compute(comp, a, d, m);
+                if dest.writes_memory() {
+                    if let Some(sanitizer) = sanitizer.as_mut() {
+                        sanitizer.write(address as u16, out, pc);
+                    }
+                    ram[address] = out;
+                }
+                let target = a;
+                if dest.bits() & 0b100 != 0 {
+                    a_from_sp = comp.reads_memory() && address == 0
+                        || a_from_sp && matches!(comp, Comp::A | Comp::APlusOne | Comp::AMinusOne);
+                    a = out;
+                }
+                if dest.bits() & 0b010 != 0 {
+                    d = out;
+                }
+                if jumps(jump, out as i16) {
+                    let target = target as usize;
+                    if target + 1 == pc
+                        && matches!(rom[target], Instruction::Load(value) if value as usize == target)
+                    {
+                        stop = Stop::Halted;
+                        break;
+                    }
+                    pc = target;
+                } else {
+                    pc += 1;
+                }
+            }
+        }
+    }
+
+    if stop != Stop::Halted && pc >= rom.len() {
+        stop = Stop::RanOffEnd;
+    }
+    Outcome {
+        stop,
+        steps,
+        ram,
+        problems: sanitizer.map_or(0, |sanitizer| sanitizer.problems),
+    }
+}
+
+/// The ALU: what a computation gives for the current registers.
+fn compute(comp: Comp, a: u16, d: u16, m: u16) -> u16 {
+    match comp {
+        Comp::Zero => 0,
+        Comp::One => 1,
+        Comp::MinusOne => u16::MAX,
+        Comp::D => d,
+        Comp::A => a,
+        Comp::M => m,
+        Comp::NotD => !d,
+        Comp::NotA => !a,
+        Comp::NotM => !m,
+        Comp::NegD => d.wrapping_neg(),
+        Comp::NegA => a.wrapping_neg(),
+        Comp::NegM => m.wrapping_neg(),
+        Comp::DPlusOne => d.wrapping_add(1),
+        Comp::APlusOne => a.wrapping_add(1),
+        Comp::MPlusOne => m.wrapping_add(1),
+        Comp::DMinusOne => d.wrapping_sub(1),
+        Comp::AMinusOne => a.wrapping_sub(1),
+        Comp::MMinusOne => m.wrapping_sub(1),
+        Comp::DPlusA => d.wrapping_add(a),
+        Comp::DPlusM => d.wrapping_add(m),
+        Comp::DMinusA => d.wrapping_sub(a),
+        Comp::DMinusM => d.wrapping_sub(m),
+        Comp::AMinusD => a.wrapping_sub(d),
+        Comp::MMinusD => m.wrapping_sub(d),
+        Comp::DAndA => d & a,
+        Comp::DAndM => d & m,
+        Comp::DOrA => d | a,
+        Comp::DOrM => d | m,
+    }
+}
+
+/// Whether a jump is taken for the computed value.
+fn jumps(jump: Jump, out: i16) -> bool {
+    let bits = jump.bits();
+    out < 0 && bits & 0b100 != 0 || out == 0 && bits & 0b010 != 0 || out > 0 && bits & 0b001 != 0
+}
+
+/// Opt-in checks on how the program uses memory, see `--sanitize`.
+struct Sanitizer<'a> {
+    symbols: &'a SymbolTable,
+    /// RAM that has been written, by the program or by `--ram`.
+    written: Vec<bool>,
+    /// Whether SP has been set to the stack yet. Until then it's still being set up.
+    stack_started: bool,
+    /// The VM command running now. Shared subroutines count as the command that jumped to them.
+    origin: Option<&'a Origin>,
+    /// The file being translated when the command was, see Chunk.
+    unit: &'a str,
+    /// Problems already reported, by kind and ROM address, so loops only report them once.
+    reported: HashSet<(&'static str, usize)>,
+    problems: usize,
+}
+
+impl<'a> Sanitizer<'a> {
+    fn new(symbols: &'a SymbolTable, options: &RunOptions) -> Self {
+        let mut written = vec![false; 32768];
+        for (address, _) in &options.ram {
+            written[*address as usize] = true;
+        }
+        Sanitizer {
+            symbols,
+            written,
+            stack_started: options
+                .ram
+                .iter()
+                .any(|(address, value)| *address == 0 && *value >= STACK_BASE as i16),
+            origin: None,
+            unit: "",
+            reported: HashSet::new(),
+            problems: 0,
+        }
+    }
+
+    /// Called before each instruction, with the chunk of code it's in.
+    fn enter(&mut self, chunk: &'a Chunk, first: bool, pc: usize, ram: &[u16]) {
+        if !first {
+            return;
+        }
+        if let Some(origin) = &chunk.origin {
+            self.origin = Some(origin);
+            self.unit = &chunk.unit;
+        }
+        // The base of this and that comes from whatever was put in pointer
+        let tokens: Vec<&str> = self.command().split_whitespace().collect();
+        if let ["push" | "pop", segment @ ("this" | "that"), index] = tokens[..] {
+            let pointer = if segment == "this" { 3 } else { 4 };
+            let address = ram[pointer].wrapping_add(index.parse::<u16>().unwrap_or(0));
+            if !(HEAP_BASE..=KBD).contains(&address) {
+                self.report(
+                    "pointer",
+                    pc,
+                    format!("{segment} {index} is RAM[{address}], outside the heap"),
+                );
+            }
+        }
+    }
+
+    /// Check a read of RAM, where `from_sp` says if the address was worked out from SP.
+    fn read(&mut self, address: u16, from_sp: bool, pc: usize, ram: &[u16]) {
+        // Calls and returns save and restore the frame, whatever is in it,
+        // and so does the bootstrap's call
+        let command = self.command();
+        if self.origin.is_none() || command.starts_with("call") || command.starts_with("return") {
+            return;
+        }
+        if address < SCREEN && !self.written[address as usize] {
+            self.report(
+                "uninitialized",
+                pc,
+                format!("reads RAM[{address}] before anything was written there"),
+            );
+        }
+        let sp = ram[0];
+        if self.stack_started && from_sp && address > sp {
+            self.report(
+                "stack",
+                pc,
+                format!("reads RAM[{address}], past the top of the stack at {sp}"),
+            );
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u16, pc: usize) {
+        self.written[address as usize] = true;
+        if address == 0 {
+            if value >= STACK_BASE {
+                self.stack_started = true;
+            } else if self.stack_started {
+                self.report(
+                    "underflow",
+                    pc,
+                    format!("sets SP to {}, below the stack", value as i16),
+                );
+            }
+        }
+        if (STATIC_BASE..STACK_BASE).contains(&address) && !self.writes_static(address) {
+            let name = self.symbols.variable_at(address).unwrap_or("no variable");
+            self.report(
+                "static",
+                pc,
+                format!("writes RAM[{address}] ({name}) in the static region"),
+            );
+        }
+    }
+
+    /// Whether the running command may write to an address in the static region.
+    ///
+    /// The translator's own variables can be written by any code, and a
+    /// file's statics only by its own `pop static`, including code it
+    /// #includes. asm blocks can write anywhere.
+    fn writes_static(&self, address: u16) -> bool {
+        let command = self.command();
+        if command == "asm" {
+            return true;
+        }
+        let Some(name) = self.symbols.variable_at(address) else {
+            return false;
+        };
+        let Some((file, index)) = name
+            .rsplit_once('.')
+            .filter(|(_, index)| index.parse::<u16>().is_ok())
+        else {
+            return true;
+        };
+        file == self.unit && command == format!("pop static {index}")
+    }
+
+    fn command(&self) -> &'a str {
+        self.origin.map_or("", |origin| origin.command.as_str())
+    }
+
+    /// Print a problem with the VM line that caused it, once per kind and ROM address.
+    fn report(&mut self, kind: &'static str, pc: usize, message: String) {
+        if !self.reported.insert((kind, pc)) {
+            return;
+        }
+        self.problems += 1;
+        match self.origin {
+            Some(origin) => println!(
+                "{}:{}: {}: {message} (ROM {pc})",
+                origin.file, origin.line, origin.command
+            ),
+            None => println!("(generated): {message} (ROM {pc})"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::code_writer::OptimizeFor;
+    use crate::hack_backend::HackBackend;
+    use crate::parser::Parser;
+    use crate::preprocessor::SourceMap;
+
+    fn options(sanitize: bool) -> RunOptions {
+        RunOptions {
+            max_steps: 100_000,
+            sanitize,
+            ram: Vec::new(),
+            show: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn runs_until_halted() {
+        let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+        let code = "function Sys.init 0\npush constant 3\npush constant 4\nadd\n\
+                    label END\ngoto END\n";
+        crate::translate_sources(&mut backend, &[("Sys", code)], Some("Sys.init"));
+        let outcome = emulate(backend.chunks(), &options(true));
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.problems, 0);
+        // The bootstrap's call frame is at 256 to 260
+        assert_eq!(outcome.ram[261], 7);
+    }
+
+    #[test]
+    fn refuses_programs_too_big_for_rom() {
+        let chunks = vec![Chunk {
+            origin: None,
+            unit: String::new(),
+            items: vec![AsmItem::value(0); ROM_SIZE + 1],
+        }];
+        let outcome = emulate(&chunks, &options(false));
+        assert_eq!(outcome.stop, Stop::TooBig(ROM_SIZE + 1));
+        assert_eq!(outcome.steps, 0);
+        assert!(!run(&chunks, &options(false)));
+    }
+
+    #[test]
+    fn included_code_can_write_the_files_statics() {
+        let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+        backend.write_prologue(None);
+        backend.set_filename("Main");
+        let code = "push constant 5\npop static 0\nlabel END\ngoto END\n";
+        // As if the first two lines were #included from lib/Counter.vm
+        let map = SourceMap::new(
+            "Main.vm",
+            vec![
+                (String::from("lib/Counter.vm"), 1),
+                (String::from("lib/Counter.vm"), 2),
+                (String::from("Main.vm"), 2),
+                (String::from("Main.vm"), 3),
+            ],
+        );
+        crate::parse_file(Parser::new(code, false), &mut backend, &map, false);
+        backend.write_epilogue();
+        let mut options = options(true);
+        options.ram.push((0, 256));
+        let outcome = emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.ram[16], 5);
+        assert_eq!(outcome.problems, 0);
+    }
+}
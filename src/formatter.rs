@@ -0,0 +1,190 @@
+#![allow(clippy::pedantic)]
+
+use crate::parser::{CommandType, Parser};
+
+/// Indentation of the commands in a function.
+const BODY_INDENT: usize = 4;
+/// Indentation of labels in a function, out from the commands so they stand out.
+const LABEL_INDENT: usize = 2;
+
+/// A line of formatted output, before trailing comments are lined up.
+enum Line {
+    Blank,
+    /// A comment on a line of its own, already indented.
+    Comment(String),
+    /// A command, already indented, with any comment after it.
+    Code(String, Option<String>),
+    /// A line of an asm block, which is left as written.
+    Verbatim(String),
+}
+
+/// Rewrite a .vm source in the canonical layout.
+///
+/// Functions start at the left with a blank line before them, their labels
+/// are indented by LABEL_INDENT and everything else by BODY_INDENT. Commands
+/// outside a function stay at the left. Tokens are separated by one space,
+/// runs of blank lines become one, and the comments after a run of commands
+/// are lined up. asm blocks are left alone.
+///
+/// Panics if the result doesn't have the same commands as the source.
+pub fn format(source: &str) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut formatted: Vec<Line> = Vec::new();
+    let mut parser = Parser::new(source, true);
+    // The first line not formatted yet
+    let mut next = 0;
+    let mut in_function = false;
+    while parser.has_more_lines() {
+        parser.advance();
+        let command_type = parser.command_type();
+        let indent = match command_type {
+            CommandType::FUNCTION => 0,
+            CommandType::LABEL if in_function => LABEL_INDENT,
+            _ if in_function => BODY_INDENT,
+            _ => 0,
+        };
+        let at = parser.current_line as usize - 1;
+        let function = matches!(command_type, CommandType::FUNCTION);
+        format_gap(
+            &lines[next..at],
+            indent,
+            function && !formatted.is_empty(),
+            &mut formatted,
+        );
+        formatted.push(code_line(lines[at], indent));
+        next = at + 1;
+        if let CommandType::ASM = command_type {
+            for line in &parser.asm_block {
+                formatted.push(Line::Verbatim(String::from(line.trim_end())));
+            }
+            next += parser.asm_block.len();
+            formatted.push(code_line(lines[next], indent));
+            next += 1;
+        }
+        in_function |= function;
+    }
+    let indent = if in_function { BODY_INDENT } else { 0 };
+    format_gap(&lines[next..], indent, false, &mut formatted);
+    while matches!(formatted.last(), Some(Line::Blank)) {
+        formatted.pop();
+    }
+
+    let text = render(&formatted);
+    if commands(&text) != commands(source) {
+        panic!("Formatting changed the commands, which is a bug in the formatter!");
+    }
+    text
+}
+
+/// Format the comments and blank lines between two commands.
+///
+/// Comments are indented like the command after them. Before a function
+/// there's always a blank line, except at the start of the file.
+fn format_gap(lines: &[&str], indent: usize, blank_first: bool, formatted: &mut Vec<Line>) {
+    if blank_first && !matches!(formatted.last(), Some(Line::Blank)) {
+        formatted.push(Line::Blank);
+    }
+    for line in lines {
+        let line = line.trim();
+        if !line.is_empty() {
+            formatted.push(Line::Comment(format!("{:indent$}{line}", "")));
+        } else if !formatted.is_empty() && !matches!(formatted.last(), Some(Line::Blank)) {
+            formatted.push(Line::Blank);
+        }
+    }
+}
+
+/// A command with single spaces between its tokens, and its comment if it has one.
+fn code_line(line: &str, indent: usize) -> Line {
+    let (code, comment) = match line.find("//") {
+        Some(start) => (&line[..start], Some(String::from(line[start..].trim_end()))),
+        None => (line, None),
+    };
+    let code = code.split_whitespace().collect::<Vec<_>>().join(" ");
+    Line::Code(format!("{:indent$}{code}", ""), comment)
+}
+
+/// Write out the lines, lining up the comments after each run of commands.
+fn render(formatted: &[Line]) -> String {
+    let mut text = String::new();
+    let mut start = 0;
+    while start < formatted.len() {
+        let end = formatted[start..]
+            .iter()
+            .position(|line| !matches!(line, Line::Code(..)))
+            .map_or(formatted.len(), |length| start + length);
+        if start == end {
+            match &formatted[start] {
+                Line::Blank => {}
+                Line::Comment(line) | Line::Verbatim(line) => text.push_str(line),
+                Line::Code(..) => unreachable!(),
+            }
+            text.push('\n');
+            start += 1;
+            continue;
+        }
+        // The comments go one space after the longest command that has one
+        let column = formatted[start..end]
+            .iter()
+            .filter_map(|line| match line {
+                Line::Code(code, Some(_)) => Some(code.len() + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        for line in &formatted[start..end] {
+            if let Line::Code(code, comment) = line {
+                match comment {
+                    Some(comment) => text.push_str(&format!("{code:column$}{comment}")),
+                    None => text.push_str(code),
+                }
+                text.push('\n');
+            }
+        }
+        start = end;
+    }
+    text
+}
+
+/// The commands in a source, with single spaces between tokens and the asm of asm blocks.
+fn commands(source: &str) -> Vec<(String, Vec<String>)> {
+    let mut parser = Parser::new(source, true);
+    let mut commands = Vec::new();
+    while parser.has_more_lines() {
+        parser.advance();
+        let code = parser.current_command.split("//").next().unwrap();
+        commands.push((
+            code.split_whitespace().collect::<Vec<_>>().join(" "),
+            parser
+                .asm_block
+                .iter()
+                .map(|line| String::from(line.trim_end()))
+                .collect(),
+        ));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = "// A counter\nfunction   Main.main 1\npush constant 0   // start\n\
+                         pop local 0\n\n\nlabel LOOP\npush local 0\npush constant 1 // one\nadd\n\
+                         pop local 0\ngoto LOOP\nasm\n  @SP\n   M=M+1\nendasm\n";
+
+    #[test]
+    fn formats_to_the_canonical_layout() {
+        let expected = "// A counter\nfunction Main.main 1\n    push constant 0 // start\n\
+                        \x20   pop local 0\n\n  label LOOP\n    push local 0\n\
+                        \x20   push constant 1 // one\n    add\n    pop local 0\n    goto LOOP\n\
+                        \x20   asm\n  @SP\n   M=M+1\n    endasm\n";
+        assert_eq!(format(MESSY), expected);
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        let formatted = format(MESSY);
+        assert_eq!(format(&formatted), formatted);
+    }
+}
@@ -0,0 +1,153 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::{self, AsmItem};
+use crate::backend::Backend;
+use crate::code_writer::{CodeWriter, OptimizeFor};
+use crate::emulator::{self, RunOptions};
+use crate::listing::{self, Chunk, Origin};
+use crate::parser::CommandType;
+
+use std::fs::File;
+use std::io::Write;
+
+/// Translates to Hack assembly, optionally with a listing alongside.
+pub struct HackBackend {
+    writer: CodeWriter,
+    chunks: Vec<Chunk>,
+    origin: Option<Origin>,
+    /// The file being translated, see Chunk.
+    unit: String,
+    bootstrapped: bool,
+    listing: bool,
+}
+
+impl HackBackend {
+    pub fn new(
+        optimize: OptimizeFor,
+        listing: bool,
+        intrinsics: Vec<String>,
+        stack_limit: Option<u16>,
+    ) -> Self {
+        HackBackend {
+            writer: CodeWriter::new(optimize, intrinsics, stack_limit),
+            chunks: Vec::new(),
+            origin: None,
+            unit: String::new(),
+            bootstrapped: false,
+            listing,
+        }
+    }
+
+    /// The code generated so far, one chunk per command.
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    /// Add the code for the current command.
+    fn push(&mut self, items: Vec<AsmItem>) {
+        self.chunks.push(Chunk {
+            origin: self.origin.take(),
+            unit: self.unit.clone(),
+            items,
+        });
+    }
+}
+
+impl Backend for HackBackend {
+    fn write_prologue(&mut self, init_function: Option<&str>) {
+        if let Some(init_function) = init_function {
+            let items = self.writer.write_bootstrap(init_function);
+            self.push(items);
+            self.bootstrapped = true;
+        }
+    }
+
+    fn write_epilogue(&mut self) {
+        if self.bootstrapped {
+            // Finish program with infinite loop
+            let items = self.writer.write_infinite_loop();
+            self.push(items);
+        }
+        let items = self.writer.write_subroutines();
+        self.push(items);
+    }
+
+    fn set_filename(&mut self, filename: &str) {
+        self.unit = String::from(filename);
+        self.writer.set_filename(filename);
+    }
+
+    fn set_origin(&mut self, origin: &Origin) {
+        self.origin = Some(origin.clone());
+    }
+
+    fn write_arithmetic(&mut self, op: &str) {
+        let items = self.writer.write_arithmetic(op);
+        self.push(items);
+    }
+
+    fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: i16) {
+        let items = self.writer.write_push_pop(command_type, segment, index);
+        self.push(items);
+    }
+
+    fn write_label(&mut self, label: &str) {
+        let items = self.writer.write_label(label);
+        self.push(items);
+    }
+
+    fn write_goto(&mut self, label: &str) {
+        let items = self.writer.write_goto(label);
+        self.push(items);
+    }
+
+    fn write_if(&mut self, label: &str) {
+        let items = self.writer.write_if(label);
+        self.push(items);
+    }
+
+    fn write_function(&mut self, function_name: &str, n_vars: i16) {
+        let items = self.writer.write_function(function_name, n_vars);
+        self.push(items);
+    }
+
+    fn write_call(&mut self, function_name: &str, n_args: i16) {
+        let items = self.writer.write_call(function_name, n_args);
+        self.push(items);
+    }
+
+    fn write_tail_call(&mut self, function_name: &str, n_args: i16) {
+        let items = self.writer.write_tail_call(function_name, n_args);
+        self.push(items);
+    }
+
+    fn write_return(&mut self) {
+        let items = self.writer.write_return();
+        self.push(items);
+    }
+
+    fn write_asm(&mut self, lines: &[String]) {
+        let items = self.writer.write_asm(lines);
+        self.push(items);
+    }
+
+    fn write_output(&self, out_path: &str) {
+        let mut out_file =
+            File::create(format!("{out_path}.asm")).expect("Unable to create new file");
+        out_file
+            .write_all(asm::serialize(&listing::items(&self.chunks)).as_bytes())
+            .expect("Error writing to file");
+
+        if self.listing {
+            let mut listing_file =
+                File::create(format!("{out_path}.lst")).expect("Unable to create new file");
+            listing_file
+                .write_all(listing::write_listing(&self.chunks).as_bytes())
+                .expect("Error writing to file");
+        }
+    }
+
+    fn run(&self, options: &RunOptions) -> bool {
+        emulator::run(&self.chunks, options)
+    }
+}
@@ -0,0 +1,416 @@
+#![allow(clippy::pedantic)]
+
+use crate::parser;
+use crate::preprocessor::SourceMap;
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+
+/// Number of slots in the temp segment.
+const TEMP_SIZE: i16 = 8;
+
+/// A leaf function that is small enough to be inlined at its call sites.
+struct Candidate {
+    /// Index of the source file the function was defined in.
+    file: usize,
+    n_vars: i16,
+    /// Body of the function, without the `function` command itself.
+    body: Vec<String>,
+    /// Number of temp slots the function itself uses (highest index + 1).
+    temp_used: i16,
+    /// Highest argument index read or written, if any.
+    max_argument: Option<i16>,
+    uses_static: bool,
+}
+
+/// Inline calls to small, non-recursive leaf functions across all sources.
+///
+/// Each source is a `(filename, contents)` pair and is rewritten in place,
+/// along with its map so lines still point at where they were written.
+/// Inlined code is mapped to the call it replaced. A function is inlined
+/// when its body has at most `threshold` commands, it makes no calls,
+/// doesn't change `pointer`, and keeps a balanced stack.
+///
+/// The callee's arguments and locals are remapped onto temp slots no function
+/// in the program uses, above the ones the callee uses itself. The inlined
+/// code makes no calls, so nothing else runs while it uses them.
+pub fn inline_calls(sources: &mut [(String, String)], maps: &mut [SourceMap], threshold: usize) {
+    let (programs, lines): (Vec<Vec<String>>, Vec<Vec<u16>>) = sources
+        .iter()
+        .map(|(_, contents)| read_commands(contents))
+        .unzip();
+    let candidates = find_candidates(&programs, threshold);
+    if candidates.is_empty() {
+        return;
+    }
+
+    // Temp is shared by every function, so any of them could be relying on a slot
+    let used_temps: HashSet<i16> = programs
+        .iter()
+        .flat_map(|commands| temps_used(commands))
+        .collect();
+    let mut inline_counter = 0;
+    for (file, commands) in programs.iter().enumerate() {
+        let mut rewritten: Vec<String> = Vec::new();
+        // The line of the source each rewritten line came from
+        let mut rewritten_lines: Vec<u16> = Vec::new();
+        for (command, line) in commands.iter().zip(&lines[file]) {
+            let tokens: Vec<&str> = command.split_whitespace().collect();
+            if tokens[0] == "call" {
+                let n_args = parser::parse_index(tokens[2]);
+                if let Some(callee) = candidates.get(tokens[1]) {
+                    if let Some(base) = frame_base(callee, file, n_args, &used_temps) {
+                        info!("inlining {} into {}", tokens[1], sources[file].0);
+                        let expanded = expand(tokens[1], callee, n_args, base, inline_counter);
+                        rewritten_lines.extend(expanded.iter().map(|_| *line));
+                        rewritten.extend(expanded);
+                        inline_counter += 1;
+                        continue;
+                    }
+                }
+            }
+            rewritten.push(command.clone());
+            rewritten_lines.push(*line);
+        }
+        sources[file].1 = rewritten.join("\n");
+        maps[file] = maps[file].remap(&rewritten_lines);
+    }
+}
+
+/// Read all commands from a .vm source, dropping comments and blank lines.
+///
+/// Also returns the line each command, or line of an asm block, is on.
+fn read_commands(contents: &str) -> (Vec<String>, Vec<u16>) {
+    // Commands are only read here, so whether extended ones are allowed is
+    // checked later when they're translated
+    let mut p = parser::Parser::new(contents, true);
+    let mut commands = Vec::new();
+    let mut lines = Vec::new();
+    while p.has_more_lines() {
+        p.advance();
+        let command: Vec<&str> = p
+            .current_command
+            .split_whitespace()
+            .take_while(|token| !token.starts_with("//"))
+            .collect();
+        if !command.is_empty() {
+            commands.push(command.join(" "));
+            lines.push(p.current_line);
+        }
+        // asm blocks are kept as they are, which also stops them being inlined
+        if command.first() == Some(&"asm") {
+            for (line, text) in (p.current_line + 1..).zip(&p.asm_block) {
+                if !text.trim().is_empty() {
+                    commands.push(text.clone());
+                    lines.push(line);
+                }
+            }
+            commands.push(String::from("endasm"));
+            lines.push(p.current_line + p.asm_block.len() as u16 + 1);
+        }
+    }
+    (commands, lines)
+}
+
+/// The temp slots a program's commands push or pop.
+fn temps_used(commands: &[String]) -> HashSet<i16> {
+    commands
+        .iter()
+        .map(|command| command.split_whitespace().collect::<Vec<&str>>())
+        .filter(|tokens| matches!(tokens[..], ["push" | "pop", "temp", _]))
+        .map(|tokens| parser::parse_index(tokens[2]))
+        .collect()
+}
+
+/// Find every function that could be inlined, keyed by function name.
+fn find_candidates(programs: &[Vec<String>], threshold: usize) -> HashMap<String, Candidate> {
+    let mut candidates = HashMap::new();
+    let mut defined: HashMap<String, usize> = HashMap::new();
+    for (file, commands) in programs.iter().enumerate() {
+        let mut i = 0;
+        while i < commands.len() {
+            let tokens: Vec<&str> = commands[i].split_whitespace().collect();
+            if tokens[0] != "function" {
+                i += 1;
+                continue;
+            }
+            let end = commands[i + 1..]
+                .iter()
+                .position(|c| c.starts_with("function "))
+                .map_or(commands.len(), |offset| i + 1 + offset);
+            let name = tokens[1].to_string();
+            *defined.entry(name.clone()).or_insert(0) += 1;
+            let body = commands[i + 1..end].to_vec();
+            if body.len() <= threshold {
+                let n_vars = parser::parse_index(tokens[2]);
+                if let Some(candidate) = analyze(file, n_vars, body) {
+                    candidates.insert(name, candidate);
+                }
+            }
+            i = end;
+        }
+    }
+    // A function defined twice can't be resolved statically.
+    candidates.retain(|name, _| defined[name] == 1);
+    candidates
+}
+
+/// Check a function body for inlining, returning None if it can't be inlined.
+fn analyze(file: usize, n_vars: i16, body: Vec<String>) -> Option<Candidate> {
+    let mut temp_used = 0;
+    let mut max_argument: Option<i16> = None;
+    let mut uses_static = false;
+    for command in &body {
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        match tokens[0] {
+            "call" | "function" | "asm" => return None,
+            "push" | "pop" => match tokens[1] {
+                "pointer" if tokens[0] == "pop" => return None,
+                "temp" => temp_used = temp_used.max(parser::parse_index(tokens[2]) + 1),
+                "argument" => max_argument = max_argument.max(Some(parser::parse_index(tokens[2]))),
+                "static" => uses_static = true,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    // Running off the end of a function falls into whatever comes next.
+    match body.last() {
+        Some(last) if last == "return" || last.starts_with("goto ") => {}
+        _ => return None,
+    }
+    if !balanced_stack(&body) {
+        return None;
+    }
+    Some(Candidate {
+        file,
+        n_vars,
+        body,
+        temp_used,
+        max_argument,
+        uses_static,
+    })
+}
+
+/// Check that every path through the body keeps the stack depth consistent
+/// and reaches each `return` with exactly the return value on the stack.
+fn balanced_stack(body: &[String]) -> bool {
+    let labels: HashMap<&str, usize> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| c.strip_prefix("label ").map(|label| (label, i)))
+        .collect();
+    let mut depth: Vec<Option<i16>> = vec![None; body.len()];
+    let mut pending: Vec<(usize, i16)> = vec![(0, 0)];
+    while let Some((i, d)) = pending.pop() {
+        if i >= body.len() {
+            return false;
+        }
+        match depth[i] {
+            Some(seen) if seen == d => continue,
+            Some(_) => return false,
+            None => depth[i] = Some(d),
+        }
+        let tokens: Vec<&str> = body[i].split_whitespace().collect();
+        let (popped, pushed) = match tokens[0] {
+            "push" => (0, 1),
+            "pop" | "if-goto" => (1, 0),
+            "neg" | "not" => (1, 1),
+            "add" | "sub" | "eq" | "gt" | "lt" | "and" | "or" | "mul" | "div" | "mod" | "shl"
+            | "shr" => (2, 1),
+            "return" => {
+                if d != 1 {
+                    return false;
+                }
+                continue;
+            }
+            "label" | "goto" => (0, 0),
+            _ => return false,
+        };
+        if d < popped {
+            return false;
+        }
+        let next = d - popped + pushed;
+        match tokens[0] {
+            "goto" | "if-goto" => match labels.get(tokens[1]) {
+                Some(&target) => pending.push((target, next)),
+                None => return false,
+            },
+            _ => {}
+        }
+        if tokens[0] != "goto" {
+            pending.push((i + 1, next));
+        }
+    }
+    true
+}
+
+/// Check whether a candidate can replace a specific `call` site, returning
+/// the first temp slot its arguments and locals can go in if so.
+///
+/// The slots have to be above the callee's own and unused by the rest of the
+/// program, which is `used_temps`.
+fn frame_base(
+    callee: &Candidate,
+    file: usize,
+    n_args: i16,
+    used_temps: &HashSet<i16>,
+) -> Option<i16> {
+    // Statics are scoped to the file the function was defined in.
+    if callee.uses_static && callee.file != file {
+        return None;
+    }
+    if callee.max_argument.is_some_and(|max| max >= n_args) {
+        return None;
+    }
+    let size = n_args + callee.n_vars;
+    (callee.temp_used..=TEMP_SIZE - size)
+        .find(|base| !(*base..base + size).any(|slot| used_temps.contains(&slot)))
+}
+
+/// Generate the commands replacing `call name n_args` with the callee's body,
+/// with its arguments and locals in temp from `argument_base` on.
+fn expand(
+    name: &str,
+    callee: &Candidate,
+    n_args: i16,
+    argument_base: i16,
+    inline_counter: usize,
+) -> Vec<String> {
+    let local_base = argument_base + n_args;
+    let label_prefix = format!("{name}.INLINE{inline_counter}");
+    let end_label = format!("{label_prefix}.END");
+    let mut commands = vec![format!("// inline {name} {n_args}")];
+
+    // Arguments were pushed in order, so the last one is on top.
+    for i in (0..n_args).rev() {
+        commands.push(format!("pop temp {}", argument_base + i));
+    }
+    for i in 0..callee.n_vars {
+        commands.push(String::from("push constant 0"));
+        commands.push(format!("pop temp {}", local_base + i));
+    }
+
+    let mut jumps_to_end = false;
+    for (i, command) in callee.body.iter().enumerate() {
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        let rewritten = match tokens[0] {
+            "push" | "pop" => match tokens[1] {
+                "argument" => format!(
+                    "{} temp {}",
+                    tokens[0],
+                    argument_base + parser::parse_index(tokens[2])
+                ),
+                "local" => format!(
+                    "{} temp {}",
+                    tokens[0],
+                    local_base + parser::parse_index(tokens[2])
+                ),
+                _ => command.clone(),
+            },
+            "label" | "goto" | "if-goto" => format!("{} {label_prefix}.{}", tokens[0], tokens[1]),
+            "return" => {
+                if i == callee.body.len() - 1 {
+                    continue;
+                }
+                jumps_to_end = true;
+                format!("goto {end_label}")
+            }
+            _ => command.clone(),
+        };
+        commands.push(rewritten);
+    }
+    if jumps_to_end {
+        commands.push(format!("label {end_label}"));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_writer::OptimizeFor;
+    use crate::emulator::{self, RunOptions, Stop};
+    use crate::hack_backend::HackBackend;
+
+    /// Run the sources, returning temp 0, temp 1 and the first static.
+    fn run(sources: &[(String, String)]) -> [u16; 3] {
+        let sources: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(filename, contents)| (filename.as_str(), contents.as_str()))
+            .collect();
+        let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+        crate::translate_sources(&mut backend, &sources, Some("Sys.init"));
+        let options = RunOptions {
+            max_steps: 10_000,
+            sanitize: true,
+            ram: Vec::new(),
+            show: Vec::new(),
+        };
+        let outcome = emulator::emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.problems, 0);
+        [outcome.ram[5], outcome.ram[6], outcome.ram[16]]
+    }
+
+    #[test]
+    fn inlining_leaves_the_programs_temps_alone() {
+        // Sys.init keeps temp 0 across the calls, including the one to
+        // Sys.quadruple, which has Sys.double inlined into it
+        let contents = "function Sys.init 0\npush constant 3\npop temp 0\npush constant 7\n\
+                        call Sys.double 1\npop temp 1\ncall Sys.quadruple 0\npop static 0\n\
+                        label END\ngoto END\n\
+                        function Sys.quadruple 0\npush constant 4\ncall Sys.double 1\n\
+                        call Sys.double 1\nreturn\n\
+                        function Sys.double 0\npush argument 0\npush argument 0\nadd\nreturn\n";
+        let original = vec![(String::from("Sys"), String::from(contents))];
+        let mut inlined = original.clone();
+        let mut maps = vec![SourceMap::identity("Sys")];
+        inline_calls(&mut inlined, &mut maps, 8);
+
+        // The argument goes above the temps the program uses
+        assert!(!inlined[0].1.contains("call Sys.double"));
+        assert!(inlined[0].1.contains("pop temp 2"));
+        assert_eq!(run(&original), [3, 14, 16]);
+        assert_eq!(run(&inlined), run(&original));
+    }
+
+    #[test]
+    fn calls_arent_inlined_without_free_temps() {
+        let mut contents = String::from("function Sys.init 0\npush constant 1\ncall Sys.id 1\n");
+        for slot in 0..TEMP_SIZE {
+            contents.push_str(&format!("pop temp {slot}\npush temp {slot}\n"));
+        }
+        contents.push_str("return\nfunction Sys.id 0\npush argument 0\nreturn\n");
+        let mut sources = vec![(String::from("Sys"), contents.clone())];
+        inline_calls(&mut sources, &mut [SourceMap::identity("Sys")], 8);
+        assert!(sources[0].1.contains("call Sys.id 1"));
+    }
+
+    #[test]
+    fn inlined_code_keeps_its_source_lines() {
+        let contents = "function Main.main 0\npush constant 3\ncall Main.double 1\nreturn\n\
+                        // doubles its argument\nfunction Main.double 0\npush argument 0\n\
+                        push argument 0\nadd\nreturn\n";
+        let mut sources = vec![(String::from("Main"), String::from(contents))];
+        // As if the code was included from another file, 100 lines down
+        let lines = (1..=11)
+            .map(|line| (String::from("Lib.vm"), line + 100))
+            .collect();
+        let mut maps = vec![SourceMap::new("Main.vm", lines)];
+        inline_calls(&mut sources, &mut maps, 8);
+
+        let rewritten: Vec<&str> = sources[0].1.lines().collect();
+        assert!(!rewritten.contains(&"call Main.double 1"));
+        // The callee's own copy of a command comes after any inlined one
+        let line_of = |command: &str| {
+            let index = rewritten.iter().rposition(|line| *line == command).unwrap();
+            maps[0].locate(index as u16 + 1)
+        };
+        assert_eq!(line_of("push constant 3"), ("Lib.vm", 102));
+        assert_eq!(line_of("// inline Main.double 1"), ("Lib.vm", 103));
+        assert_eq!(line_of("pop temp 0"), ("Lib.vm", 103));
+        assert_eq!(line_of("function Main.double 0"), ("Lib.vm", 106));
+        assert_eq!(line_of("add"), ("Lib.vm", 109));
+    }
+}
@@ -0,0 +1,665 @@
+#![allow(clippy::pedantic)]
+
+use crate::preprocessor::SourceMap;
+use log::info;
+use std::collections::HashMap;
+use std::path::Path;
+
+const KEYWORDS: [&str; 21] = [
+    "class",
+    "constructor",
+    "function",
+    "method",
+    "field",
+    "static",
+    "var",
+    "int",
+    "char",
+    "boolean",
+    "void",
+    "true",
+    "false",
+    "null",
+    "this",
+    "let",
+    "do",
+    "if",
+    "else",
+    "while",
+    "return",
+];
+
+const SYMBOLS: &str = "{}()[].,;+-*/&|<>=~";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Keyword,
+    Symbol,
+    Integer,
+    String,
+    Identifier,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: Kind,
+    text: String,
+    line: u16,
+}
+
+/// Split Jack source into tokens, dropping whitespace and comments.
+fn tokenize(file: &str, source: &str) -> Vec<Token> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let kind = if c == '\n' {
+            line += 1;
+            i += 1;
+            continue;
+        } else if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if chars[i..].starts_with(&['/', '/']) {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            continue;
+        } else if chars[i..].starts_with(&['/', '*']) {
+            let comment_line = line;
+            i += 2;
+            while !chars[i..].starts_with(&['*', '/']) {
+                match chars.get(i) {
+                    Some('\n') => line += 1,
+                    Some(_) => {}
+                    None => panic!("{file}:{comment_line}: comment has no closing */"),
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        } else if SYMBOLS.contains(c) {
+            i += 1;
+            Kind::Symbol
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            Kind::Integer
+        } else if c == '"' {
+            i += 1;
+            while chars.get(i) != Some(&'"') {
+                if matches!(chars.get(i), Some('\n') | None) {
+                    panic!("{file}:{line}: string has no closing \"");
+                }
+                i += 1;
+            }
+            i += 1;
+            Kind::String
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            Kind::Identifier
+        } else {
+            panic!("{file}:{line}: unexpected character {c:?}");
+        };
+        let mut text: String = chars[start..i].iter().collect();
+        let kind = match kind {
+            Kind::Identifier if KEYWORDS.contains(&text.as_str()) => Kind::Keyword,
+            Kind::Integer if text.parse::<u16>().map_or(true, |value| value > 32767) => {
+                panic!("{file}:{line}: {text} is bigger than 32767")
+            }
+            Kind::String => {
+                text = String::from(&text[1..text.len() - 1]);
+                Kind::String
+            }
+            kind => kind,
+        };
+        tokens.push(Token { kind, text, line });
+    }
+    tokens
+}
+
+/// A variable, stored in a VM segment.
+#[derive(Clone)]
+struct Symbol {
+    segment: &'static str,
+    type_name: String,
+    index: usize,
+}
+
+/// Compiles one Jack class into VM commands, following Chapter 11.
+struct Compiler<'a> {
+    file: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    class: String,
+    /// Statics and fields.
+    class_symbols: HashMap<String, Symbol>,
+    /// Arguments and locals of the subroutine being compiled.
+    symbols: HashMap<String, Symbol>,
+    /// Counter for labels, which only need to be unique within a function.
+    labels: usize,
+    /// Line of the statement being compiled, for the source map.
+    line: u16,
+    code: Vec<(String, u16)>,
+}
+
+/// Compile a .jack file into VM code.
+///
+/// Returns the code and the Jack line each VM command came from.
+pub fn compile(filename: &str, source: &str) -> (String, SourceMap) {
+    let file = format!("{filename}.jack");
+    let mut compiler = Compiler {
+        file: &file,
+        tokens: tokenize(&file, source),
+        position: 0,
+        class: String::new(),
+        class_symbols: HashMap::new(),
+        symbols: HashMap::new(),
+        labels: 0,
+        line: 1,
+        code: Vec::new(),
+    };
+    compiler.compile_class();
+    let stem = Path::new(filename).file_name().unwrap().to_str().unwrap();
+    if compiler.class != stem {
+        panic!(
+            "{file}: class {} should be in {}.jack",
+            compiler.class, compiler.class
+        );
+    }
+    info!("Compiled {file} into {} VM commands", compiler.code.len());
+    let mut text = String::new();
+    let mut lines = Vec::new();
+    for (command, line) in compiler.code {
+        text.push_str(&command);
+        text.push('\n');
+        lines.push((file.clone(), line));
+    }
+    (text, SourceMap::new(&file, lines))
+}
+
+impl Compiler<'_> {
+    /// Stop with a message pointing at the current token.
+    fn error(&self, message: &str) -> ! {
+        match self.tokens.get(self.position) {
+            Some(token) => panic!(
+                "{}:{}: {message}, found {:?}",
+                self.file, token.line, token.text
+            ),
+            None => panic!("{}: {message}, found the end of the file", self.file),
+        }
+    }
+
+    /// Is the current token this keyword or symbol?
+    fn at(&self, text: &str) -> bool {
+        self.tokens.get(self.position).is_some_and(|token| {
+            matches!(token.kind, Kind::Keyword | Kind::Symbol) && token.text == text
+        })
+    }
+
+    fn advance(&mut self) -> Token {
+        let Some(token) = self.tokens.get(self.position).cloned() else {
+            self.error("expected more code");
+        };
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, text: &str) {
+        if !self.at(text) {
+            self.error(&format!("expected {text:?}"));
+        }
+        self.position += 1;
+    }
+
+    fn identifier(&mut self) -> String {
+        match self.tokens.get(self.position) {
+            Some(token) if token.kind == Kind::Identifier => self.advance().text,
+            _ => self.error("expected a name"),
+        }
+    }
+
+    /// A type: int, char, boolean or a class name.
+    fn type_name(&mut self) -> String {
+        if self.at("int") || self.at("char") || self.at("boolean") {
+            self.advance().text
+        } else {
+            self.identifier()
+        }
+    }
+
+    /// Start compiling a statement or declaration on the current line.
+    fn start_line(&mut self) {
+        if let Some(token) = self.tokens.get(self.position) {
+            self.line = token.line;
+        }
+    }
+
+    fn emit(&mut self, command: String) {
+        self.code.push((command, self.line));
+    }
+
+    fn define(&mut self, name: String, type_name: String, segment: &'static str) {
+        let scope = if matches!(segment, "static" | "this") {
+            &mut self.class_symbols
+        } else {
+            &mut self.symbols
+        };
+        let index = scope
+            .values()
+            .filter(|symbol| symbol.segment == segment)
+            .count();
+        if scope.contains_key(&name) {
+            self.position -= 1;
+            self.error("already declared");
+        }
+        scope.insert(
+            name,
+            Symbol {
+                segment,
+                type_name,
+                index,
+            },
+        );
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.symbols
+            .get(name)
+            .or_else(|| self.class_symbols.get(name))
+            .cloned()
+    }
+
+    fn push_variable(&mut self, symbol: &Symbol) {
+        self.emit(format!("push {} {}", symbol.segment, symbol.index));
+    }
+
+    fn compile_class(&mut self) {
+        self.expect("class");
+        self.class = self.identifier();
+        self.expect("{");
+        while self.at("static") || self.at("field") {
+            self.compile_class_var_dec();
+        }
+        while self.at("constructor") || self.at("function") || self.at("method") {
+            self.compile_subroutine();
+        }
+        self.expect("}");
+        if self.position < self.tokens.len() {
+            self.error("expected the end of the file");
+        }
+    }
+
+    fn compile_class_var_dec(&mut self) {
+        let segment = if self.advance().text == "static" {
+            "static"
+        } else {
+            "this"
+        };
+        self.compile_var_names(segment);
+    }
+
+    /// `type name (, name)* ;` as in class variable and local declarations.
+    fn compile_var_names(&mut self, segment: &'static str) {
+        let type_name = self.type_name();
+        loop {
+            let name = self.identifier();
+            self.define(name, type_name.clone(), segment);
+            if !self.at(",") {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(";");
+    }
+
+    fn compile_subroutine(&mut self) {
+        self.start_line();
+        let kind = self.advance().text;
+        if self.at("void") {
+            self.advance();
+        } else {
+            self.type_name();
+        }
+        let name = self.identifier();
+        self.symbols.clear();
+        self.labels = 0;
+        if kind == "method" {
+            self.define(String::from("this"), self.class.clone(), "argument");
+        }
+        self.expect("(");
+        if !self.at(")") {
+            loop {
+                let type_name = self.type_name();
+                let param = self.identifier();
+                self.define(param, type_name, "argument");
+                if !self.at(",") {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(")");
+        self.expect("{");
+        while self.at("var") {
+            self.advance();
+            self.compile_var_names("local");
+        }
+        let n_locals = self
+            .symbols
+            .values()
+            .filter(|symbol| symbol.segment == "local")
+            .count();
+        self.emit(format!("function {}.{} {}", self.class, name, n_locals));
+        match kind.as_str() {
+            "constructor" => {
+                let n_fields = self
+                    .class_symbols
+                    .values()
+                    .filter(|symbol| symbol.segment == "this")
+                    .count();
+                self.emit(format!("push constant {n_fields}"));
+                self.emit(String::from("call Memory.alloc 1"));
+                self.emit(String::from("pop pointer 0"));
+            }
+            "method" => {
+                self.emit(String::from("push argument 0"));
+                self.emit(String::from("pop pointer 0"));
+            }
+            _ => {}
+        }
+        self.compile_statements();
+        self.expect("}");
+    }
+
+    fn compile_statements(&mut self) {
+        loop {
+            self.start_line();
+            if self.at("let") {
+                self.compile_let();
+            } else if self.at("if") {
+                self.compile_if();
+            } else if self.at("while") {
+                self.compile_while();
+            } else if self.at("do") {
+                self.compile_do();
+            } else if self.at("return") {
+                self.compile_return();
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn compile_let(&mut self) {
+        self.expect("let");
+        let name = self.identifier();
+        let Some(symbol) = self.lookup(&name) else {
+            self.position -= 1;
+            self.error("undeclared variable");
+        };
+        if self.at("[") {
+            self.advance();
+            self.push_variable(&symbol);
+            self.compile_expression();
+            self.expect("]");
+            self.emit(String::from("add"));
+            self.expect("=");
+            self.compile_expression();
+            self.expect(";");
+            // The value goes in temp while the address is moved to THAT
+            self.emit(String::from("pop temp 0"));
+            self.emit(String::from("pop pointer 1"));
+            self.emit(String::from("push temp 0"));
+            self.emit(String::from("pop that 0"));
+        } else {
+            self.expect("=");
+            self.compile_expression();
+            self.expect(";");
+            self.emit(format!("pop {} {}", symbol.segment, symbol.index));
+        }
+    }
+
+    fn compile_if(&mut self) {
+        let line = self.line;
+        let n = self.labels;
+        self.labels += 1;
+        self.expect("if");
+        self.expect("(");
+        self.compile_expression();
+        self.expect(")");
+        self.emit(String::from("not"));
+        self.emit(format!("if-goto IF_FALSE{n}"));
+        self.expect("{");
+        self.compile_statements();
+        self.expect("}");
+        self.line = line;
+        if self.at("else") {
+            self.emit(format!("goto IF_END{n}"));
+            self.emit(format!("label IF_FALSE{n}"));
+            self.advance();
+            self.expect("{");
+            self.compile_statements();
+            self.expect("}");
+            self.line = line;
+            self.emit(format!("label IF_END{n}"));
+        } else {
+            self.emit(format!("label IF_FALSE{n}"));
+        }
+    }
+
+    fn compile_while(&mut self) {
+        let line = self.line;
+        let n = self.labels;
+        self.labels += 1;
+        self.expect("while");
+        self.emit(format!("label WHILE_EXP{n}"));
+        self.expect("(");
+        // `while (true)` needs no test, so `while (true) {}` is the loop that halts Hack programs
+        if self.at("true")
+            && self
+                .tokens
+                .get(self.position + 1)
+                .map(|token| token.text.as_str())
+                == Some(")")
+        {
+            self.advance();
+        } else {
+            self.compile_expression();
+            self.emit(String::from("not"));
+            self.emit(format!("if-goto WHILE_END{n}"));
+        }
+        self.expect(")");
+        self.expect("{");
+        self.compile_statements();
+        self.expect("}");
+        self.line = line;
+        self.emit(format!("goto WHILE_EXP{n}"));
+        self.emit(format!("label WHILE_END{n}"));
+    }
+
+    fn compile_do(&mut self) {
+        self.expect("do");
+        let name = self.identifier();
+        self.compile_call(&name);
+        self.expect(";");
+        // Throw away the return value
+        self.emit(String::from("pop temp 0"));
+    }
+
+    fn compile_return(&mut self) {
+        self.expect("return");
+        if self.at(";") {
+            // void functions still return something
+            self.emit(String::from("push constant 0"));
+        } else {
+            self.compile_expression();
+        }
+        self.expect(";");
+        self.emit(String::from("return"));
+    }
+
+    /// Terms joined by operators, evaluated left to right.
+    fn compile_expression(&mut self) {
+        self.compile_term();
+        loop {
+            let command = match self.tokens.get(self.position) {
+                Some(token) if token.kind == Kind::Symbol => match token.text.as_str() {
+                    "+" => "add",
+                    "-" => "sub",
+                    "*" => "call Math.multiply 2",
+                    "/" => "call Math.divide 2",
+                    "&" => "and",
+                    "|" => "or",
+                    "<" => "lt",
+                    ">" => "gt",
+                    "=" => "eq",
+                    _ => return,
+                },
+                _ => return,
+            };
+            self.advance();
+            self.compile_term();
+            self.emit(String::from(command));
+        }
+    }
+
+    fn compile_term(&mut self) {
+        if self.position >= self.tokens.len() {
+            self.error("expected an expression");
+        }
+        let token = self.advance();
+        match (token.kind, token.text.as_str()) {
+            (Kind::Integer, value) => self.emit(format!("push constant {value}")),
+            (Kind::String, text) => {
+                self.emit(format!("push constant {}", text.chars().count()));
+                self.emit(String::from("call String.new 1"));
+                for c in text.chars() {
+                    self.emit(format!("push constant {}", c as u32));
+                    self.emit(String::from("call String.appendChar 2"));
+                }
+            }
+            (Kind::Keyword, "true") => {
+                self.emit(String::from("push constant 0"));
+                self.emit(String::from("not"));
+            }
+            (Kind::Keyword, "false" | "null") => self.emit(String::from("push constant 0")),
+            (Kind::Keyword, "this") => self.emit(String::from("push pointer 0")),
+            (Kind::Symbol, "(") => {
+                self.compile_expression();
+                self.expect(")");
+            }
+            (Kind::Symbol, "-") => {
+                self.compile_term();
+                self.emit(String::from("neg"));
+            }
+            (Kind::Symbol, "~") => {
+                self.compile_term();
+                self.emit(String::from("not"));
+            }
+            (Kind::Identifier, name) => {
+                if self.at("(") || self.at(".") {
+                    self.compile_call(name);
+                    return;
+                }
+                let Some(symbol) = self.lookup(name) else {
+                    self.position -= 1;
+                    self.error("undeclared variable");
+                };
+                self.push_variable(&symbol);
+                if self.at("[") {
+                    self.advance();
+                    self.compile_expression();
+                    self.expect("]");
+                    self.emit(String::from("add"));
+                    self.emit(String::from("pop pointer 1"));
+                    self.emit(String::from("push that 0"));
+                }
+            }
+            _ => {
+                self.position -= 1;
+                self.error("expected an expression");
+            }
+        }
+    }
+
+    /// A subroutine call, after its first name has been read.
+    fn compile_call(&mut self, name: &str) {
+        let (function, mut n_args) = if self.at(".") {
+            self.advance();
+            let subroutine = self.identifier();
+            match self.lookup(name) {
+                // A method called on an object in a variable
+                Some(symbol) => {
+                    self.push_variable(&symbol);
+                    (format!("{}.{subroutine}", symbol.type_name), 1)
+                }
+                None => (format!("{name}.{subroutine}"), 0),
+            }
+        } else {
+            // A method of this class, called on this object
+            self.emit(String::from("push pointer 0"));
+            (format!("{}.{name}", self.class), 1)
+        };
+        self.expect("(");
+        if !self.at(")") {
+            loop {
+                self.compile_expression();
+                n_args += 1;
+                if !self.at(",") {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(")");
+        self.emit(format!("call {function} {n_args}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_writer::OptimizeFor;
+    use crate::emulator::{self, RunOptions, Stop};
+    use crate::hack_backend::HackBackend;
+
+    #[test]
+    fn compiled_code_runs() {
+        let source = "class Main {\n    function void main() {\n        var int i, sum;\n\
+                      \x20       let i = 1;\n        while (i < 11) {\n\
+                      \x20           let sum = sum + (i * i);\n            let i = i + 1;\n\
+                      \x20       }\n        do Memory.poke(3000, sum);\n        return;\n    }\n}\n";
+        let (code, map) = compile("Main", source);
+        // The poke is on line 9
+        let poke = code
+            .lines()
+            .position(|line| line == "push constant 3000")
+            .unwrap();
+        assert_eq!(map.locate(poke as u16 + 1), ("Main.jack", 9));
+
+        let mut sources = vec![(String::from("Main"), code)];
+        crate::os::link(&mut sources, Some("Sys.init"));
+        let sources: Vec<(&str, &str)> = sources
+            .iter()
+            .map(|(filename, contents)| (filename.as_str(), contents.as_str()))
+            .collect();
+        let mut backend = HackBackend::new(OptimizeFor::Size, false, Vec::new(), None);
+        crate::translate_sources(&mut backend, &sources, Some("Sys.init"));
+        let options = RunOptions {
+            max_steps: 10_000_000,
+            sanitize: false,
+            ram: Vec::new(),
+            show: Vec::new(),
+        };
+        let outcome = emulator::emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        assert_eq!(outcome.ram[3000], 385);
+    }
+}
@@ -0,0 +1,304 @@
+#![allow(clippy::pedantic)]
+
+use crate::parser::{self, CommandType, Parser};
+use std::collections::{BTreeMap, HashSet};
+
+/// Something a warning can be about, each of which can be turned off.
+///
+/// Indices are compared as numbers, so `temp 01` and `temp 1` are the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Rule {
+    /// The first command after a goto or return in a function, when no label comes
+    /// between them
+    Unreachable,
+    /// A label that no goto or if-goto in the same function names
+    UnusedLabel,
+    /// A function declaring more locals than the highest local index it pushes or
+    /// pops, plus one
+    UnusedLocals,
+    /// A static index the file pops to but never pushes, at the first pop
+    UnreadStatic,
+    /// A static index the file pushes but never pops to, so it's always 0, at the
+    /// first push
+    UnwrittenStatic,
+    /// A push of a temp index that no earlier line of the function pops to
+    UninitializedTemp,
+    /// A function whose last command isn't a goto or return
+    MissingReturn,
+}
+
+impl Rule {
+    /// The rule's name, as given to --disable.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::Unreachable => "unreachable",
+            Rule::UnusedLabel => "unused-label",
+            Rule::UnusedLocals => "unused-locals",
+            Rule::UnreadStatic => "unread-static",
+            Rule::UnwrittenStatic => "unwritten-static",
+            Rule::UninitializedTemp => "uninitialized-temp",
+            Rule::MissingReturn => "missing-return",
+        }
+    }
+}
+
+/// A warning about a line of a .vm file.
+pub struct Warning {
+    pub line: u16,
+    pub rule: Rule,
+    pub message: String,
+}
+
+/// A command and the line it's on.
+struct Command<'a> {
+    line: u16,
+    command_type: CommandType,
+    tokens: Vec<&'a str>,
+}
+
+impl Command<'_> {
+    /// The segment index of a push or pop, if it has one the parser accepts.
+    ///
+    /// `push constant` has a value instead, so it has no index.
+    fn index(&self) -> Option<i16> {
+        match self.tokens.get(1) {
+            Some(&"constant") => None,
+            _ => self.tokens.get(2).and_then(|index| parser::index(index)),
+        }
+    }
+}
+
+/// Find code in a .vm file that's valid but probably not what was meant.
+///
+/// Each function is checked on its own, from its `function` to the next.
+/// Temps are checked in the order the code is written, not the order it
+/// runs in. Warnings for the rules in `disabled` are left out.
+pub fn lint(contents: &str, disabled: &[Rule]) -> Vec<Warning> {
+    // Tokens are taken from the contents, which outlive the parser
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut commands = Vec::new();
+    let mut p = Parser::new(contents, true);
+    while p.has_more_lines() {
+        p.advance();
+        let code = lines[p.current_line as usize - 1]
+            .split("//")
+            .next()
+            .unwrap();
+        commands.push(Command {
+            line: p.current_line,
+            command_type: p.command_type(),
+            tokens: code.split_whitespace().collect(),
+        });
+    }
+
+    let mut warnings = Vec::new();
+    let starts: Vec<usize> = commands
+        .iter()
+        .enumerate()
+        .filter(|(_, command)| matches!(command.command_type, CommandType::FUNCTION))
+        .map(|(index, _)| index)
+        .collect();
+    for (i, start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(commands.len());
+        lint_function(&commands[*start..end], &mut warnings);
+    }
+    lint_statics(&commands, &mut warnings);
+
+    warnings.retain(|warning| !disabled.contains(&warning.rule));
+    warnings.sort_by_key(|warning| warning.line);
+    warnings
+}
+
+fn lint_function(commands: &[Command<'_>], warnings: &mut Vec<Warning>) {
+    let function = &commands[0];
+    let name = function.tokens[1];
+    let targets: HashSet<&str> = commands
+        .iter()
+        .filter(|command| matches!(command.command_type, CommandType::GOTO | CommandType::IF))
+        .map(|command| command.tokens[1])
+        .collect();
+    let mut reachable = true;
+    // Only the first of a run of unreachable commands is reported
+    let mut reported = false;
+    let mut highest_local: Option<i32> = None;
+    let mut temps_written: HashSet<i16> = HashSet::new();
+    for command in &commands[1..] {
+        match command.command_type {
+            CommandType::LABEL => {
+                reachable = true;
+                if !targets.contains(command.tokens[1]) {
+                    warnings.push(Warning {
+                        line: command.line,
+                        rule: Rule::UnusedLabel,
+                        message: format!("nothing in {name} jumps to label {}", command.tokens[1]),
+                    });
+                }
+                continue;
+            }
+            _ if !reachable && !reported => {
+                warnings.push(Warning {
+                    line: command.line,
+                    rule: Rule::Unreachable,
+                    message: String::from("this can't be reached"),
+                });
+                reported = true;
+            }
+            _ => {}
+        }
+        match (command.tokens[0], command.tokens.get(1).copied()) {
+            ("push" | "pop", Some("local")) => {
+                highest_local = highest_local.max(command.index().map(i32::from));
+            }
+            ("push", Some("temp")) => match command.index() {
+                Some(index) if !temps_written.contains(&index) => {
+                    warnings.push(Warning {
+                        line: command.line,
+                        rule: Rule::UninitializedTemp,
+                        message: format!("temp {index} is read before {name} writes it"),
+                    });
+                }
+                _ => {}
+            },
+            ("pop", Some("temp")) => {
+                temps_written.extend(command.index());
+            }
+            _ => {}
+        }
+        if matches!(
+            command.command_type,
+            CommandType::GOTO | CommandType::RETURN
+        ) {
+            reachable = false;
+            reported = false;
+        }
+    }
+    // A reachable end means the function falls through into whatever follows
+    if reachable {
+        let last = commands.last().unwrap();
+        warnings.push(Warning {
+            line: last.line,
+            rule: Rule::MissingReturn,
+            message: format!("{name} can run off its end without returning"),
+        });
+    }
+    let n_vars = function
+        .tokens
+        .get(2)
+        .and_then(|n| parser::index(n))
+        .map_or(0, i32::from);
+    let used = highest_local.map_or(0, |index| index + 1);
+    if n_vars > used {
+        warnings.push(Warning {
+            line: function.line,
+            rule: Rule::UnusedLocals,
+            message: match highest_local {
+                Some(index) => {
+                    format!("{name} declares {n_vars} locals but uses none past local {index}")
+                }
+                None => format!("{name} declares {n_vars} locals but uses none"),
+            },
+        });
+    }
+}
+
+/// Warn about statics the file only writes or only reads, at the first time it does.
+fn lint_statics(commands: &[Command<'_>], warnings: &mut Vec<Warning>) {
+    // The first line each static is written and read on
+    let mut written: BTreeMap<i16, u16> = BTreeMap::new();
+    let mut read: BTreeMap<i16, u16> = BTreeMap::new();
+    for command in commands {
+        let (Some("static"), Some(index)) = (command.tokens.get(1).copied(), command.index())
+        else {
+            continue;
+        };
+        match command.tokens[0] {
+            "pop" => {
+                written.entry(index).or_insert(command.line);
+            }
+            "push" => {
+                read.entry(index).or_insert(command.line);
+            }
+            _ => {}
+        }
+    }
+    for (index, line) in &written {
+        if !read.contains_key(index) {
+            warnings.push(Warning {
+                line: *line,
+                rule: Rule::UnreadStatic,
+                message: format!("static {index} is written but never read"),
+            });
+        }
+    }
+    for (index, line) in &read {
+        if !written.contains_key(index) {
+            warnings.push(Warning {
+                line: *line,
+                rule: Rule::UnwrittenStatic,
+                message: format!("static {index} is read but never written, so it's always 0"),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rule and line of each warning.
+    fn rules(contents: &str) -> Vec<(&'static str, u16)> {
+        lint(contents, &[])
+            .iter()
+            .map(|warning| (warning.rule.name(), warning.line))
+            .collect()
+    }
+
+    #[test]
+    fn indices_are_compared_as_numbers() {
+        let code = "function Main.main 0\npop temp 01\npush temp 1\npop static 002\n\
+                    push static 2\nreturn\n";
+        assert_eq!(rules(code), []);
+    }
+
+    #[test]
+    fn indices_the_parser_refuses_are_ignored() {
+        // Only push constant takes hex or values past 32767
+        let code = "function Main.main 0\npop temp +1\npush temp 1\npop static 0x2\n\
+                    push static 40000\npush constant 0x7\npop temp 7\npush constant 7\n\
+                    return\n";
+        let warnings = lint(code, &[]);
+        let lines: Vec<(u16, Rule)> = warnings.iter().map(|w| (w.line, w.rule)).collect();
+        assert_eq!(lines, [(3, Rule::UninitializedTemp)]);
+    }
+
+    #[test]
+    fn statics_only_read_or_only_written_are_reported() {
+        let code = "function Main.main 0\npush static 0\npop static 1\npush static 0\n\
+                    return\n";
+        assert_eq!(rules(code), [("unwritten-static", 2), ("unread-static", 3)]);
+        assert_eq!(
+            lint(code, &[Rule::UnwrittenStatic])
+                .iter()
+                .map(|warning| warning.rule)
+                .collect::<Vec<_>>(),
+            [Rule::UnreadStatic]
+        );
+    }
+
+    #[test]
+    fn function_rules() {
+        let code = "function Main.main 2\npush temp 3\npop local 0\nlabel UNUSED\n\
+                    push constant 0\nreturn\npush constant 1\nlabel LOOP\ngoto LOOP\n\
+                    function Main.fall 0\npush constant 0\n";
+        assert_eq!(
+            rules(code),
+            [
+                ("unused-locals", 1),
+                ("uninitialized-temp", 2),
+                ("unused-label", 4),
+                ("unreachable", 7),
+                ("missing-return", 11),
+            ]
+        );
+    }
+}
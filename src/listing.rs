@@ -0,0 +1,153 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm::AsmItem;
+use crate::assembler::{self, SymbolTable};
+
+/// The VM command some assembly was generated from.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    pub file: String,
+    pub line: u16,
+    pub command: String,
+}
+
+/// The assembly generated for one VM command.
+///
+/// Code the translator adds on its own, like the bootstrap, has no origin.
+pub struct Chunk {
+    pub origin: Option<Origin>,
+    /// The file being translated, whose name its statics start with. An
+    /// #include can make this differ from the origin's file.
+    pub unit: String,
+    pub items: Vec<AsmItem>,
+}
+
+/// Flatten chunks into the program's assembly items.
+pub fn items(chunks: &[Chunk]) -> Vec<AsmItem> {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk.items.iter().cloned())
+        .collect()
+}
+
+/// Generate an annotated listing of the program.
+///
+/// Every instruction gets its ROM address, assembly, binary encoding and the
+/// VM command it came from. Labels are shown with the address they resolve to.
+pub fn write_listing(chunks: &[Chunk]) -> String {
+    let mut symbols = SymbolTable::new(&items(chunks));
+    let mut write_string = format!(
+        "{:<5}  {:<32}  {:<16}  {}\n",
+        "ROM", "INSTRUCTION", "BINARY", "SOURCE"
+    );
+    let mut rom_address = 0;
+    for chunk in chunks {
+        let source = match &chunk.origin {
+            Some(origin) => format!("{}:{} {}", origin.file, origin.line, origin.command),
+            None => String::from("(generated)"),
+        };
+        for item in &chunk.items {
+            match item {
+                AsmItem::Label(label) => write_string.push_str(
+                    format!(
+                        "{:<5}  {:<32}  {:<16}  {source}\n",
+                        "",
+                        format!("({label})"),
+                        format!("= {rom_address}")
+                    )
+                    .as_str(),
+                ),
+                AsmItem::Comment(_) => {}
+                _ => {
+                    let word = assembler::encode(item, &mut symbols).unwrap();
+                    write_string.push_str(
+                        format!(
+                            "{rom_address:05}  {:<32}  {word:016b}  {source}\n",
+                            item.to_string()
+                        )
+                        .as_str(),
+                    );
+                    rom_address += 1;
+                }
+            }
+        }
+    }
+    write_string
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_writer::OptimizeFor;
+    use crate::hack_backend::HackBackend;
+    use std::ops::Range;
+
+    #[test]
+    fn vm_lines_map_to_their_rom_addresses() {
+        let code = "function Sys.init 0\npush constant 7\npop temp 0\nlabel END\ngoto END\n";
+        let mut backend = HackBackend::new(OptimizeFor::Speed, true, Vec::new(), None);
+        crate::translate_sources(&mut backend, &[("Sys", code)], Some("Sys.init"));
+        let program = assembler::assemble(&items(backend.chunks()));
+        let listing = write_listing(backend.chunks());
+
+        // Runs of instructions from the same source, and where each label resolves
+        let mut ranges: Vec<(String, Range<usize>)> = Vec::new();
+        let mut labels = Vec::new();
+        for line in listing.lines().skip(1) {
+            let (address, rest) = line.split_at(5);
+            let instruction = rest[2..34].trim_end();
+            let binary = rest[36..52].trim_end();
+            let source = rest[54..].to_string();
+            if address.trim().is_empty() {
+                labels.push((
+                    instruction.to_string(),
+                    binary[2..].parse().unwrap(),
+                    source,
+                ));
+                continue;
+            }
+            let address: usize = address.parse().unwrap();
+            assert_eq!(
+                usize::from_str_radix(binary, 2).unwrap(),
+                program[address] as usize
+            );
+            match ranges.last_mut() {
+                Some((last, range)) if *last == source => {
+                    assert_eq!(range.end, address);
+                    range.end += 1;
+                }
+                _ => {
+                    let start = ranges.last().map_or(0, |(_, range)| range.end);
+                    assert_eq!(start, address);
+                    ranges.push((source, address..address + 1));
+                }
+            }
+        }
+        assert_eq!(ranges.last().unwrap().1.end, program.len());
+
+        let sources: Vec<&str> = ranges.iter().map(|(source, _)| source.as_str()).collect();
+        assert_eq!(
+            sources,
+            [
+                "(generated)",
+                "Sys.vm:2 push constant 7",
+                "Sys.vm:3 pop temp 0",
+                "Sys.vm:5 goto END",
+                "(generated)"
+            ]
+        );
+        let lengths: Vec<usize> = ranges[1..4].iter().map(|(_, range)| range.len()).collect();
+        assert_eq!(lengths, [7, 5, 2]);
+        let start = ranges[1].1.start;
+        assert!(labels.contains(&(
+            String::from("(Sys.init)"),
+            start,
+            String::from("Sys.vm:1 function Sys.init 0")
+        )));
+        assert!(labels.contains(&(
+            String::from("(Sys.init$END)"),
+            ranges[3].1.start,
+            String::from("Sys.vm:4 label END")
+        )));
+    }
+}
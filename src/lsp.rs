@@ -0,0 +1,465 @@
+#![allow(clippy::pedantic)]
+
+use crate::asm;
+use crate::backend::Backend;
+use crate::code_writer::OptimizeFor;
+use crate::hack_backend::HackBackend;
+use crate::parser::Parser;
+use crate::preprocessor::SourceMap;
+use crate::validator;
+use glob::glob;
+use log::info;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// LSP severities for diagnostics.
+const ERROR: u8 = 1;
+const WARNING: u8 = 2;
+/// LSP symbol kind for functions.
+const FUNCTION: u8 = 12;
+
+/// Something wrong with a line of a document, counting lines from 0 like LSP.
+struct Problem {
+    line: usize,
+    severity: u8,
+    message: String,
+}
+
+/// A Language Server Protocol server for .vm files.
+struct Server {
+    /// Text of the open documents, by URI, which wins over what's on disk.
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+/// Serve LSP over stdin and stdout until the client says to exit.
+///
+/// Documents are translated to Hack on every change, and anything that
+/// would stop the translation is reported as an error on its line.
+pub fn serve() {
+    // Errors are caught and sent to the client, so keep them off stderr
+    panic::set_hook(Box::new(|_| {}));
+    let mut server = Server {
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+    let mut input = io::stdin().lock();
+    while let Some(message) = read_message(&mut input) {
+        server.handle(&message);
+    }
+}
+
+impl Server {
+    fn handle(&mut self, message: &Value) {
+        let Some(method) = message["method"].as_str() else {
+            // Responses to requests, which the server never sends
+            return;
+        };
+        info!("LSP {method}");
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let result = match method {
+            "initialize" => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": env!("CARGO_PKG_NAME") },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Some(Value::Null)
+            }
+            "exit" => std::process::exit(if self.shutdown { 0 } else { 1 }),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(String::from(uri), String::from(text));
+                self.publish_diagnostics(uri);
+                None
+            }
+            "textDocument/didChange" => {
+                // With full sync the last change is the whole document
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    self.documents.insert(String::from(uri), String::from(text));
+                    self.publish_diagnostics(uri);
+                }
+                None
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                send(&notification(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                ));
+                None
+            }
+            "textDocument/hover" => Some(self.hover(uri, line_of(params))),
+            "textDocument/definition" => Some(self.definition(uri, line_of(params))),
+            "textDocument/documentSymbol" => Some(self.symbols(uri)),
+            _ => None,
+        };
+        let Some(id) = message.get("id") else {
+            return;
+        };
+        send(&match result {
+            Some(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            None => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": format!("{method} isn't supported") },
+            }),
+        });
+    }
+
+    /// The text of a document, from the client if it's open or else from disk.
+    fn text(&self, uri: &str) -> Option<String> {
+        match self.documents.get(uri) {
+            Some(text) => Some(text.clone()),
+            None => fs::read_to_string(uri_to_path(uri)?).ok(),
+        }
+    }
+
+    fn publish_diagnostics(&self, uri: &str) {
+        let text = self.text(uri).unwrap_or_default();
+        let diagnostics: Vec<Value> = translate(uri, &text)
+            .0
+            .into_iter()
+            .map(|problem| {
+                json!({
+                    "range": line_range(&text, problem.line),
+                    "severity": problem.severity,
+                    "source": env!("CARGO_PKG_NAME"),
+                    "message": problem.message,
+                })
+            })
+            .collect();
+        send(&notification(
+            "textDocument/publishDiagnostics",
+            json!({ "uri": uri, "diagnostics": diagnostics }),
+        ));
+    }
+
+    /// The Hack assembly generated for the command on a line.
+    fn hover(&self, uri: &str, line: usize) -> Value {
+        let text = self.text(uri).unwrap_or_default();
+        match translate(uri, &text).1.get(&line) {
+            Some(code) => json!({
+                "contents": { "kind": "markdown", "value": format!("```\n{code}```") },
+                "range": line_range(&text, line),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    /// Where the function called or the label jumped to on a line is.
+    ///
+    /// Functions are looked for in every .vm file in the document's folder,
+    /// and labels in the function the line is in.
+    fn definition(&self, uri: &str, line: usize) -> Value {
+        let text = self.text(uri).unwrap_or_default();
+        let lines: Vec<&str> = text.lines().collect();
+        let Some(command) = lines.get(line).map(|line| tokens(line)) else {
+            return Value::Null;
+        };
+        match command[..] {
+            ["call", function, ..] => {
+                for uri in self.project(uri) {
+                    let text = self.text(&uri).unwrap_or_default();
+                    if let Some(line) = text
+                        .lines()
+                        .position(|line| matches!(tokens(line)[..], ["function", name, ..] if name == function))
+                    {
+                        return json!({ "uri": uri, "range": line_range(&text, line) });
+                    }
+                }
+                Value::Null
+            }
+            ["goto" | "if-goto", label, ..] => {
+                // Labels are scoped to the function, so only look between its neighbours
+                let is_function = |line: &&str| tokens(line).first() == Some(&"function");
+                let start = lines[..line]
+                    .iter()
+                    .rposition(is_function)
+                    .map_or(0, |start| start + 1);
+                let end = lines[line..]
+                    .iter()
+                    .position(is_function)
+                    .map_or(lines.len(), |end| line + end);
+                match (start..end)
+                    .find(|index| matches!(tokens(lines[*index])[..], ["label", name, ..] if name == label))
+                {
+                    Some(line) => json!({ "uri": uri, "range": line_range(&text, line) }),
+                    None => Value::Null,
+                }
+            }
+            _ => Value::Null,
+        }
+    }
+
+    /// The functions in a document, each running up to the next.
+    fn symbols(&self, uri: &str) -> Value {
+        let text = self.text(uri).unwrap_or_default();
+        let lines: Vec<&str> = text.lines().collect();
+        let starts: Vec<(usize, &str)> = lines
+            .iter()
+            .enumerate()
+            .filter_map(|(index, line)| match tokens(line)[..] {
+                ["function", name, ..] => Some((index, name)),
+                _ => None,
+            })
+            .collect();
+        let symbols: Vec<Value> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, (start, name))| {
+                let end = starts.get(i + 1).map_or(lines.len(), |(next, _)| *next) - 1;
+                json!({
+                    "name": name,
+                    "kind": FUNCTION,
+                    "range": {
+                        "start": { "line": start, "character": 0 },
+                        "end": { "line": end, "character": utf16_len(lines[end]) },
+                    },
+                    "selectionRange": line_range(&text, *start),
+                })
+            })
+            .collect();
+        Value::Array(symbols)
+    }
+
+    /// The URIs of the .vm files in the same folder as a document, open or not.
+    fn project(&self, uri: &str) -> Vec<String> {
+        let Some(folder) = uri_to_path(uri).and_then(|path| path.parent().map(Path::to_path_buf))
+        else {
+            return vec![String::from(uri)];
+        };
+        let mut uris: Vec<String> = glob(&format!("{}/*.vm", folder.display()))
+            .expect("Failed to read glob pattern")
+            .flatten()
+            .map(|path| path_to_uri(&path))
+            .collect();
+        for open in self.documents.keys() {
+            let in_folder =
+                uri_to_path(open).is_some_and(|path| path.parent() == Some(folder.as_path()));
+            if in_folder && !uris.contains(open) {
+                uris.push(open.clone());
+            }
+        }
+        uris
+    }
+}
+
+/// Translate a document to Hack, as the only file of a program.
+///
+/// Returns the problems found and the assembly generated for each line,
+/// counting lines from 0.
+fn translate(uri: &str, text: &str) -> (Vec<Problem>, HashMap<usize, String>) {
+    let filename = uri_to_path(uri)
+        .and_then(|path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| String::from("Main"));
+    let map = SourceMap::identity(&filename);
+    let mut backend = HackBackend::new(OptimizeFor::Speed, false, Vec::new(), None);
+    backend.set_filename(&filename);
+    let mut problems = Vec::new();
+    let mut p = Parser::new(text, true);
+    loop {
+        match catch(|| {
+            let more = p.has_more_lines();
+            if more {
+                p.advance();
+            }
+            more
+        }) {
+            Ok(true) => {}
+            Ok(false) => break,
+            // An asm block with no end, which is the rest of the file
+            Err(message) => {
+                problems.push(Problem {
+                    line: p.current_line as usize - 1,
+                    severity: ERROR,
+                    message,
+                });
+                break;
+            }
+        }
+        // Checked on its own, so a command that can't be translated doesn't hide the rest
+        if let Ok(Some(message)) = catch(|| validator::problem(&p)) {
+            problems.push(Problem {
+                line: p.current_line as usize - 1,
+                severity: WARNING,
+                message,
+            });
+        }
+        backend.set_origin(&crate::origin(&p, &map));
+        if let Err(message) = catch(|| crate::write_command(&mut p, &mut backend, false)) {
+            problems.push(Problem {
+                line: p.current_line as usize - 1,
+                severity: ERROR,
+                message,
+            });
+        }
+    }
+    let mut code: HashMap<usize, String> = HashMap::new();
+    for chunk in backend.chunks() {
+        if let Some(origin) = &chunk.origin {
+            code.entry(origin.line as usize - 1)
+                .or_default()
+                .push_str(&asm::serialize(&chunk.items));
+        }
+    }
+    (problems, code)
+}
+
+/// Run something that might panic, giving back the panic message if it does.
+fn catch<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| {
+                payload
+                    .downcast_ref::<&str>()
+                    .map(|message| String::from(*message))
+            })
+            .unwrap_or_else(|| String::from("translation failed"))
+    })
+}
+
+/// The tokens of a line of VM code, leaving out any comment.
+fn tokens(line: &str) -> Vec<&str> {
+    line.split("//")
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .collect()
+}
+
+/// The line of the position in a request's parameters.
+fn line_of(params: &Value) -> usize {
+    params["position"]["line"].as_u64().unwrap_or(0) as usize
+}
+
+/// An LSP range covering a whole line.
+fn line_range(text: &str, line: usize) -> Value {
+    let length = text.lines().nth(line).map_or(0, utf16_len);
+    json!({
+        "start": { "line": line, "character": 0 },
+        "end": { "line": line, "character": length },
+    })
+}
+
+/// The length of a line in UTF-16 code units, which LSP counts characters in.
+fn utf16_len(line: &str) -> usize {
+    line.encode_utf16().count()
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+/// Read a message, returning None once the input ends.
+///
+/// A message that isn't JSON comes back as Null, and is ignored.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn send(message: &Value) {
+    let body = message.to_string();
+    let mut output = io::stdout().lock();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())
+        .expect("Error writing to stdout");
+    output.flush().expect("Error writing to stdout");
+}
+
+/// The path of a `file://` URI.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut path = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = (encoded[i] == b'%')
+            .then(|| encoded.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                path.push(byte);
+                i += 3;
+            }
+            None => {
+                path.push(encoded[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(path).ok()?))
+}
+
+/// The `file://` URI of a path, escaping anything that isn't allowed as is.
+fn path_to_uri(path: &Path) -> String {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_are_kept_when_translation_fails() {
+        let text = "function Main.main 0\npush constant 2\npush constant 3\nmul\n\
+                    pop nowhere 0\npush constant 0\nreturn\n";
+        let (problems, code) = translate("file:///tmp/Main.vm", text);
+        let found: Vec<(usize, u8)> = problems
+            .iter()
+            .map(|problem| (problem.line, problem.severity))
+            .collect();
+        assert_eq!(found, [(3, WARNING), (4, ERROR)]);
+        // The lines after the error are still translated
+        assert!(code.contains_key(&6));
+    }
+
+    #[test]
+    fn ranges_count_utf16_code_units() {
+        let text = "push constant 1 // caf\u{e9} \u{1f600}\n";
+        assert_eq!(line_range(text, 0)["end"]["character"], 26);
+    }
+}
@@ -1,177 +1,992 @@
 #![allow(clippy::pedantic)]
 
+mod asm;
+mod assembler;
+mod backend;
+mod c_writer;
+mod callgraph;
 mod code_writer;
+mod decompiler;
+mod disassembler;
+mod emulator;
+mod formatter;
+mod hack_backend;
+mod inliner;
+mod jack;
+mod linter;
+mod listing;
+mod lsp;
+mod os;
 mod parser;
+mod preprocessor;
+mod validator;
+mod wat_writer;
+mod x86_writer;
 
-use std::env;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use code_writer::CodeWriter;
+use backend::{Backend, Target};
+use c_writer::CWriter;
+use callgraph::{CallGraph, GraphFormat};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use code_writer::OptimizeFor;
+use emulator::RunOptions;
 use glob::glob;
-use indoc::formatdoc;
+use hack_backend::HackBackend;
+use linter::Rule;
+use listing::Origin;
+use preprocessor::SourceMap;
+use wat_writer::WatWriter;
+use x86_writer::X86Writer;
 
 #[derive(Parser)]
 #[command(about = "Compile .vm files into .asm files for Nand2Tetris")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    /// File or Folder of .vm files to parse to .asm
-    path: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// File or Folder of .vm or .jack files to parse to .asm
+    #[arg(required = true)]
+    path: Option<PathBuf>,
 
     /// Turn off bootstrap code (used for earlier examples)
     #[arg(short, long, default_value_t = false)]
     skip_bootstrap: bool,
+
+    /// Inline calls to small non-recursive leaf functions
+    #[arg(long, default_value_t = false)]
+    inline: bool,
+
+    /// Largest function body (in VM commands) that will be inlined
+    #[arg(long, default_value_t = 8)]
+    inline_threshold: usize,
+
+    /// Reuse the current frame for calls immediately followed by return
+    #[arg(long, default_value_t = false)]
+    tail_calls: bool,
+
+    /// Whether to favour speed or size where code can be generated either way
+    #[arg(long, value_enum, default_value_t = OptimizeFor::Speed)]
+    optimize: OptimizeFor,
+
+    /// What to translate the VM code into
+    #[arg(long, value_enum, default_value_t = Target::Hack)]
+    target: Target,
+
+    /// Allow mul, div, mod, shl and shr as arithmetic commands
+    #[arg(long, default_value_t = false)]
+    extended_ops: bool,
+
+    /// Also write the .vm code compiled from each .jack file next to it
+    #[arg(long, default_value_t = false)]
+    emit_vm: bool,
+
+    /// Don't link in the bundled Jack OS classes the program calls but doesn't define
+    #[arg(long, default_value_t = false)]
+    no_os: bool,
+
+    /// Replace calls to some bundled OS functions with hand-written Hack code
+    #[arg(long, default_value_t = false)]
+    intrinsics: bool,
+
+    /// Check the stack on every push, pop and call, halting with an error code if it overflows
+    #[arg(long, default_value_t = false)]
+    checked: bool,
+
+    /// Highest SP allowed with --checked, by default where the heap starts
+    #[arg(long, default_value_t = 2048, value_parser = clap::value_parser!(u16).range(257..=16384))]
+    stack_limit: u16,
+
+    /// Expand #include, #define and #macro directives before translating
+    #[arg(long, default_value_t = false)]
+    preprocess: bool,
+
+    /// Reject anything that isn't standard VM code, like asm blocks and extended commands
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+
+    /// Also write a .lst listing with ROM addresses, binary and VM source lines
+    #[arg(long, default_value_t = false)]
+    listing: bool,
+
+    /// Run the translated program in the Hack emulator until it halts
+    #[arg(long, default_value_t = false)]
+    run: bool,
+
+    /// Most instructions to run before giving up on the program halting
+    #[arg(long, default_value_t = 100_000_000)]
+    max_steps: u64,
+
+    /// Set RAM before running, as address=value
+    #[arg(long, value_parser = parse_ram, requires = "run")]
+    ram: Vec<(u16, i16)>,
+
+    /// Print a RAM address once the program stops running
+    #[arg(long, requires = "run", value_parser = clap::value_parser!(u16).range(..32768))]
+    show: Vec<u16>,
+
+    /// Report suspicious memory accesses while running, with the VM line that made them
+    #[arg(long, default_value_t = false, requires = "run")]
+    sanitize: bool,
+
+    /// Keep running, translating again whenever the input changes
+    #[arg(long, default_value_t = false)]
+    watch: bool,
+}
+
+/// How often --watch looks for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Subcommand)]
+enum Command {
+    /// Decode a .hack file back into Hack assembly, printed to stdout
+    Disasm {
+        /// .hack file to disassemble
+        path: PathBuf,
+    },
+    /// Recover .vm files from a .asm file generated by this translator
+    Decompile {
+        /// .asm file to decompile
+        path: PathBuf,
+
+        /// Folder to write the .vm files to, by default <name>_decompiled next to the .asm
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+    },
+    /// Rewrite .vm files in a canonical layout
+    Fmt {
+        /// .vm files, or folders of them, to format
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Don't write anything, but fail if any file isn't formatted
+        #[arg(long, default_value_t = false)]
+        check: bool,
+    },
+    /// Warn about .vm code that's valid but probably a mistake
+    Lint {
+        /// .vm file, or folder of them, to check
+        path: PathBuf,
+
+        /// Turn a rule off, can be given more than once
+        #[arg(long, value_enum)]
+        disable: Vec<Rule>,
+
+        /// Print the warnings as a JSON array
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+    /// Serve the Language Server Protocol for .vm files over stdio
+    Lsp,
+    /// Print which functions call which, marking cycles and unreachable or undefined functions
+    Callgraph {
+        /// .vm or .jack file, or folder of them, to graph
+        path: PathBuf,
+
+        /// Whether to print Graphviz DOT or JSON
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
 }
 
 fn main() {
     env_logger::init();
     let cli = Cli::parse();
-    let args: Vec<String> = env::args().collect();
-    let mut file_contents = String::new();
-    let f_or_d = cli.path;
-    let mut out_file: File;
-    let mut call_counter: i16 = -1;
+    if let Err(error) = check_usage(&cli) {
+        error.exit();
+    }
+    match &cli.command {
+        Some(Command::Disasm { path }) => disasm(path),
+        Some(Command::Decompile { path, out_dir }) => decompile(path, out_dir.as_deref()),
+        Some(Command::Fmt { paths, check }) => fmt(paths, *check),
+        Some(Command::Lint {
+            path,
+            disable,
+            json,
+        }) => lint(path, disable, *json),
+        Some(Command::Lsp) => lsp::serve(),
+        Some(Command::Callgraph { path, format }) => callgraph(path, *format),
+        None if cli.watch => watch(&cli),
+        None => {
+            if !translate(&cli) {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Check the options go together, beyond what clap's attributes can say.
+fn check_usage(cli: &Cli) -> Result<(), clap::Error> {
+    if cli.target == Target::Hack {
+        return Ok(());
+    }
+    let hack_only = [
+        (cli.checked, "--checked"),
+        (cli.run, "--run"),
+        (cli.listing, "--listing"),
+        (cli.intrinsics, "--intrinsics"),
+    ];
+    match hack_only.iter().find(|(given, _)| *given) {
+        Some((_, option)) => Err(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            format!("{option} only works with --target hack"),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Print the assembly for a .hack file.
+fn disasm(path: &Path) {
+    let text = fs::read_to_string(path).expect("File not found");
+    let items = disassembler::disassemble(&disassembler::read_hack(&text));
+    print!("{}", asm::serialize(&items));
+}
+
+/// Write the .vm files recovered from a .asm file.
+fn decompile(path: &Path, out_dir: Option<&Path>) {
+    let text = fs::read_to_string(path).expect("File not found");
+    let name = path.file_stem().unwrap().to_str().unwrap();
+    let out_dir = match out_dir {
+        Some(out_dir) => out_dir.to_path_buf(),
+        None => path.with_file_name(format!("{name}_decompiled")),
+    };
+    fs::create_dir_all(&out_dir).expect("Unable to create output folder");
+    for file in decompiler::decompile(&asm::parse(&text), name) {
+        let mut contents = file.commands.join("\n");
+        contents.push('\n');
+        fs::write(out_dir.join(format!("{}.vm", file.name)), contents)
+            .expect("Error writing to file");
+    }
+}
+
+/// Format .vm files, or with `check` list the ones that aren't formatted.
+fn fmt(paths: &[PathBuf], check: bool) {
+    let files: Vec<PathBuf> = paths.iter().flat_map(|path| vm_files(path)).collect();
+    let mut unformatted = 0;
+    for file in &files {
+        let source = fs::read_to_string(file).expect("File not found");
+        let formatted = formatter::format(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} isn't formatted", file.display());
+            unformatted += 1;
+        } else {
+            fs::write(file, formatted).expect("Error writing to file");
+            println!("Formatted {}", file.display());
+        }
+    }
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Print warnings for a .vm file or a folder of them, failing if there are any.
+fn lint(path: &Path, disabled: &[Rule], json: bool) {
+    let mut found = Vec::new();
+    for file in vm_files(path) {
+        let contents = fs::read_to_string(&file).expect("File not found");
+        for warning in linter::lint(&contents, disabled) {
+            found.push((file.display().to_string(), warning));
+        }
+    }
+    if json {
+        let warnings: Vec<serde_json::Value> = found
+            .iter()
+            .map(|(file, warning)| {
+                serde_json::json!({
+                    "file": file,
+                    "line": warning.line,
+                    "rule": warning.rule.name(),
+                    "message": warning.message,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&warnings).unwrap());
+    } else {
+        for (file, warning) in &found {
+            println!(
+                "{file}:{}: {}: {}",
+                warning.line,
+                warning.rule.name(),
+                warning.message
+            );
+        }
+    }
+    if !found.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Print the call graph of a program, compiling any .jack files first.
+fn callgraph(path: &Path, format: GraphFormat) {
+    let mut files = vm_files(path);
+    // Graph .jack files in place of the .vm files compiled from them
+    files.retain(|file| !file.with_extension("jack").exists());
+    if path.is_dir() {
+        let pattern = format!("{}/*.jack", path.to_str().unwrap());
+        files.extend(
+            glob(&pattern)
+                .expect("Failed to read glob pattern")
+                .flatten(),
+        );
+    }
+    let sources: Vec<(String, SourceMap)> = files
+        .iter()
+        .map(|file| {
+            let contents = fs::read_to_string(file).expect("File not found");
+            let filename = file.file_stem().unwrap().to_str().unwrap();
+            if file
+                .extension()
+                .is_some_and(|extension| extension == "jack")
+            {
+                jack::compile(filename, &contents)
+            } else {
+                (contents, SourceMap::identity(filename))
+            }
+        })
+        .collect();
+    let graph = CallGraph::build(&sources);
+    match format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&graph.to_json()).unwrap()
+        ),
+    }
+}
+
+/// A .vm file, or the .vm files in a folder.
+fn vm_files(path: &Path) -> Vec<PathBuf> {
+    if path.is_dir() {
+        let pattern = format!("{}/*.vm", path.to_str().unwrap());
+        glob(&pattern)
+            .expect("Failed to read glob pattern")
+            .flatten()
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    }
+}
+
+/// Translate a .vm file or a folder of them.
+///
+/// Returns false if the code was rejected by --strict or the target, or the program
+/// failed --run.
+fn translate(cli: &Cli) -> bool {
+    let f_or_d = cli.path.as_ref().unwrap();
+    let path = f_or_d.to_str().unwrap();
+    let out_path: String;
+    // Folder that included files are found relative to
+    let base: &Path;
+    let init_function: &str;
+    let mut sources: Vec<(String, String)> = Vec::new();
+    let mut paths: Vec<PathBuf> = Vec::new();
     if f_or_d.is_dir() {
-        let dir: &str = &args[1];
-        out_file =
-            File::create(format!("{}/{}.asm", &args[1], dir)).expect("Unable to create new file");
-
-        if !cli.skip_bootstrap {
-            let mut c: CodeWriter = CodeWriter::new("Sys", &mut call_counter); // Boostrap code calls the Sys init function
-            let bootstrap_code = formatdoc! {
-                "@256
-        D=A
-        @SP
-        M=D
-        {}
-        ", c.write_call("Sys.init", 0)
-            };
-
-            out_file
-                .write(bootstrap_code.as_bytes())
-                .expect("Error writing to file");
-        }
-
-        for files in glob(format!("{}/*.vm", dir).as_str()).expect("Failed to read glob pattern") {
+        let dir: &str = path;
+        out_path = format!("{}/{}", path, dir);
+        base = f_or_d;
+        // Boostrap code calls the Sys init function
+        init_function = "Sys.init";
+
+        for files in glob(format!("{}/*.*", dir).as_str()).expect("Failed to read glob pattern") {
             match files {
-                Ok(path) => {
+                // Compile .jack files rather than reading any .vm files compiled from them before
+                Ok(path)
+                    if path.extension().is_some_and(|extension| extension == "vm")
+                        && !path.with_extension("jack").exists()
+                        || path
+                            .extension()
+                            .is_some_and(|extension| extension == "jack") =>
+                {
                     let mut file_contents = String::new();
                     let mut file = File::open(path.clone()).expect("Error opening file");
                     file.read_to_string(&mut file_contents)
                         .expect("Could not read file");
-                    let p = parser::Parser::new(&file_contents);
-                    let c = CodeWriter::new(
-                        path.file_name()
-                            .unwrap()
-                            .to_str()
-                            .unwrap()
-                            .split('.')
-                            .nth(0)
-                            .unwrap(),
-                        &mut call_counter,
-                    );
-
-                    out_file = parse_file(p, c, out_file);
+                    let filename = path
+                        .file_name()
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .split('.')
+                        .next()
+                        .unwrap();
+                    sources.push((String::from(filename), file_contents));
+                    paths.push(path);
                 }
+                Ok(_) => {}
                 Err(e) => println!("{:?}", e),
             }
         }
-
-        if !cli.skip_bootstrap {
-            // Finish program with infinite loop
-            let infinite_loop = formatdoc! {"
-        (INFINITE_LOOP)
-        @INFINITE_LOOP
-        0;JMP            // infinite loop
-    "};
-            out_file
-                .write(infinite_loop.as_bytes())
-                .expect("Error writing to file");
-        }
     } else {
-        let mut file = File::open(&args[1]).expect("File not found");
+        let mut file_contents = String::new();
+        let mut file = File::open(path).expect("File not found");
         file.read_to_string(&mut file_contents)
             .expect("Could not read file");
-        let p = parser::Parser::new(&file_contents);
-        let file_str = format!("{}", &args[1].split('.').nth(0).unwrap());
-        let filename: &str = file_str.as_str();
-        let mut c: CodeWriter = CodeWriter::new(&filename, &mut call_counter);
-        out_file = File::create(format!("{}.asm", &filename)).expect("Unable to create new file");
-
-        if !cli.skip_bootstrap {
-            // Set up bootstrap code
-            let bootstrap_code = formatdoc! {
-                "@256
-        D=A
-        @SP
-        M=D
-        {}
-        ", c.write_call("init", 0)
-            };
-            out_file
-                .write(bootstrap_code.as_bytes())
-                .expect("Error writing to file");
-        }
-
-        out_file = parse_file(p, c, out_file);
-
-        if !cli.skip_bootstrap {
-            // Finish program with infinite loop
-            let infinite_loop = formatdoc! {"
-        (INFINITE_LOOP)
-        @INFINITE_LOOP
-        0;JMP            // infinite loop
-    "};
-            out_file
-                .write(infinite_loop.as_bytes())
-                .expect("Error writing to file");
-        }
-    }
-}
-
-fn parse_file(mut p: parser::Parser<'_>, mut c: CodeWriter<'_>, mut out_file: File) -> File {
+        let filename = path.split('.').next().unwrap().to_string();
+        out_path = filename.clone();
+        // The filename already has the folder in it
+        base = Path::new(".");
+        init_function = "init";
+
+        sources.push((filename, file_contents));
+        paths.push(f_or_d.clone());
+    }
+
+    let mut maps: Vec<SourceMap> = sources
+        .iter()
+        .map(|(filename, _)| SourceMap::identity(filename))
+        .collect();
+    for (((filename, contents), map), path) in sources.iter_mut().zip(maps.iter_mut()).zip(&paths) {
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "jack")
+        {
+            (*contents, *map) = jack::compile(filename, contents);
+            if cli.emit_vm {
+                fs::write(path.with_extension("vm"), &contents).expect("Error writing to file");
+            }
+        } else if cli.preprocess {
+            (*contents, *map) = preprocessor::preprocess(base, filename, contents);
+        }
+    }
+
+    let mut linked = Vec::new();
+    if !cli.no_os {
+        linked = os::link(
+            &mut sources,
+            if cli.skip_bootstrap {
+                None
+            } else {
+                Some(init_function)
+            },
+        );
+        for (filename, _) in &sources[maps.len()..] {
+            maps.push(SourceMap::identity(filename));
+        }
+    }
+
+    if cli.intrinsics {
+        for function in unlinked_intrinsics(&sources, &linked) {
+            eprintln!(
+                "warning: calls to {function} don't use its intrinsic, as its class isn't the bundled OS's (see --no-os)"
+            );
+        }
+    }
+
+    if cli.strict {
+        let problems: Vec<String> = sources
+            .iter()
+            .zip(&maps)
+            .flat_map(|((_, contents), map)| validator::non_standard(contents, map))
+            .collect();
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("{problem}");
+            }
+            return false;
+        }
+    }
+
+    if cli.target != Target::Hack {
+        let blocks: Vec<String> = sources
+            .iter()
+            .zip(&maps)
+            .flat_map(|((_, contents), map)| validator::asm_blocks(contents, map))
+            .collect();
+        if !blocks.is_empty() {
+            for block in &blocks {
+                eprintln!("{block}");
+            }
+            return false;
+        }
+    }
+
+    if cli.inline {
+        inliner::inline_calls(&mut sources, &mut maps, cli.inline_threshold);
+    }
+
+    let mut backend: Box<dyn Backend> = match cli.target {
+        Target::Hack => Box::new(HackBackend::new(
+            cli.optimize,
+            cli.listing,
+            if cli.intrinsics { linked } else { Vec::new() },
+            cli.checked.then_some(cli.stack_limit),
+        )),
+        Target::C => Box::new(CWriter::new()),
+        Target::Wat => Box::new(WatWriter::new()),
+        Target::X86_64 => Box::new(X86Writer::new()),
+    };
+
+    backend.write_prologue(if cli.skip_bootstrap {
+        None
+    } else {
+        Some(init_function)
+    });
+    for ((filename, file_contents), map) in sources.iter().zip(&maps) {
+        let p = parser::Parser::new(file_contents, cli.extended_ops);
+        backend.set_filename(filename);
+        parse_file(p, backend.as_mut(), map, cli.tail_calls);
+    }
+    backend.write_epilogue();
+
+    backend.write_output(&out_path);
+
+    if cli.run {
+        let options = RunOptions {
+            max_steps: cli.max_steps,
+            sanitize: cli.sanitize,
+            ram: cli.ram.clone(),
+            show: cli.show.clone(),
+        };
+        return backend.run(&options);
+    }
+    true
+}
+
+/// Functions with an intrinsic that are called but can't use it, as their class wasn't linked from the OS.
+fn unlinked_intrinsics(sources: &[(String, String)], linked: &[String]) -> BTreeSet<String> {
+    let mut unlinked = BTreeSet::new();
+    for (_, contents) in sources {
+        let mut p = parser::Parser::new(contents, true);
+        while p.has_more_lines() {
+            p.advance();
+            if !matches!(p.command_type(), parser::CommandType::CALL) {
+                continue;
+            }
+            let function = p.arg1().unwrap();
+            let class = function.split('.').next().unwrap();
+            if code_writer::has_intrinsic(function) && !linked.iter().any(|other| other == class) {
+                unlinked.insert(String::from(function));
+            }
+        }
+    }
+    unlinked
+}
+
+/// Translate again whenever the input changes, until stopped with Ctrl-C.
+///
+/// Errors are printed rather than ending the process, so the next save can fix them.
+fn watch(cli: &Cli) -> ! {
+    // The panic's location is in the translator, so only the message helps
+    panic::set_hook(Box::new(|info| {
+        eprintln!(
+            "error: {}",
+            info.payload_as_str().unwrap_or("translation failed")
+        );
+    }));
+    let path = cli.path.as_ref().unwrap();
+    let mut last = None;
+    loop {
+        if let Some(inputs) = changed_inputs(path, cli.preprocess, last.as_ref()) {
+            let start = Instant::now();
+            match panic::catch_unwind(|| translate(cli)) {
+                Ok(true) => println!("Translated {} in {:.2?}", path.display(), start.elapsed()),
+                _ => println!("Translating {} failed", path.display()),
+            }
+            println!("Watching for changes, press Ctrl-C to stop");
+            last = Some(inputs);
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// The inputs --watch looks at, if it should translate them again.
+///
+/// That's the first time, and whenever an input has been added, removed or
+/// modified since `last`.
+fn changed_inputs(
+    path: &Path,
+    preprocess: bool,
+    last: Option<&BTreeMap<PathBuf, Option<SystemTime>>>,
+) -> Option<BTreeMap<PathBuf, Option<SystemTime>>> {
+    let inputs = watched_files(path, preprocess);
+    (last != Some(&inputs)).then_some(inputs)
+}
+
+/// The inputs --watch looks at, with when each was last modified.
+///
+/// That's the given file, or the .vm and .jack files in the given folder,
+/// plus every .vm file under the folder with --preprocess as any could be
+/// included. .vm files next to a .jack file are left out, as they're
+/// compiled from it and --emit-vm writes them on every translation.
+fn watched_files(path: &Path, preprocess: bool) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    let folder = match path.parent() {
+        _ if path.is_dir() => path,
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut patterns = Vec::new();
+    if path.is_dir() {
+        patterns.push(format!("{}/*.vm", folder.display()));
+        patterns.push(format!("{}/*.jack", folder.display()));
+    }
+    if preprocess {
+        patterns.push(format!("{}/**/*.vm", folder.display()));
+    }
+    let modified = |file: &Path| {
+        fs::metadata(file)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let mut files: BTreeMap<PathBuf, Option<SystemTime>> = patterns
+        .iter()
+        .flat_map(|pattern| {
+            glob(pattern)
+                .expect("Failed to read glob pattern")
+                .flatten()
+        })
+        .filter(|file| {
+            !(file.extension().is_some_and(|extension| extension == "vm")
+                && file.with_extension("jack").exists())
+        })
+        .map(|file| {
+            let time = modified(&file);
+            (file, time)
+        })
+        .collect();
+    if !path.is_dir() {
+        files.insert(path.to_path_buf(), modified(path));
+    }
+    files
+}
+
+/// Parse an `address=value` argument for --ram.
+fn parse_ram(arg: &str) -> Result<(u16, i16), String> {
+    let (address, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected address=value, found {arg}"))?;
+    let address: u16 = address
+        .parse()
+        .ok()
+        .filter(|address| *address < 32768)
+        .ok_or_else(|| format!("{address} isn't a RAM address"))?;
+    let value: i16 = value
+        .parse()
+        .map_err(|_| format!("{value} isn't a 16-bit value"))?;
+    Ok((address, value))
+}
+
+/// Translate every command in a file with the given backend.
+fn parse_file(
+    mut p: parser::Parser<'_>,
+    backend: &mut dyn Backend,
+    map: &SourceMap,
+    tail_calls: bool,
+) {
     while p.has_more_lines() {
         p.advance();
-        match p.command_type() {
-            parser::CommandType::ARITHMETIC => out_file
-                .write(c.write_arithmetic(p.arg1().unwrap()).as_bytes())
-                .expect("Error writing to file"),
-            parser::CommandType::PUSH | parser::CommandType::POP => out_file
-                .write(
-                    c.write_push_pop(
-                        p.command_type(),
-                        p.arg1().unwrap(),
-                        p.clone().arg2().unwrap(),
-                    )
-                    .as_bytes(),
-                )
-                .expect("Error writing to file"),
-            parser::CommandType::LABEL => out_file
-                .write(c.write_label(p.arg1().unwrap()).as_bytes())
-                .expect("Error writing to file"),
-            parser::CommandType::GOTO => out_file
-                .write(c.write_goto(p.arg1().unwrap()).as_bytes())
-                .expect("Error writing to file"),
-            parser::CommandType::IF => out_file
-                .write(c.write_if(p.arg1().unwrap()).as_bytes())
-                .expect("Error writing to file"),
-            parser::CommandType::FUNCTION => out_file
-                .write(
-                    c.write_function(p.arg1().unwrap(), p.clone().arg2().unwrap())
-                        .as_bytes(),
-                )
-                .expect("Error writing to file"),
-            parser::CommandType::RETURN => out_file
-                .write(c.write_return().as_bytes())
-                .expect("Error writing to file"),
-            parser::CommandType::CALL => out_file
-                .write(
-                    c.write_call(p.arg1().unwrap(), p.clone().arg2().unwrap())
-                        .as_bytes(),
-                )
-                .expect("Error writing to file"),
+        backend.set_origin(&origin(&p, map));
+        write_command(&mut p, backend, tail_calls);
+    }
+}
+
+/// Where the parser's current command came from.
+fn origin(p: &parser::Parser<'_>, map: &SourceMap) -> Origin {
+    let (file, line) = map.locate(p.current_line);
+    Origin {
+        file: String::from(file),
+        line,
+        command: p
+            .current_command
+            .split("//")
+            .next()
+            .unwrap()
+            .trim()
+            .to_string(),
+    }
+}
+
+/// Translate the parser's current command with the given backend.
+///
+/// A tail call also takes the `return` after it, which moves the parser on.
+fn write_command(p: &mut parser::Parser<'_>, backend: &mut dyn Backend, tail_calls: bool) {
+    match p.command_type() {
+        parser::CommandType::ARITHMETIC => backend.write_arithmetic(p.arg1().unwrap()),
+        parser::CommandType::PUSH | parser::CommandType::POP => backend.write_push_pop(
+            p.command_type(),
+            p.arg1().unwrap(),
+            p.clone().arg2().unwrap(),
+        ),
+        parser::CommandType::LABEL => backend.write_label(p.arg1().unwrap()),
+        parser::CommandType::GOTO => backend.write_goto(p.arg1().unwrap()),
+        parser::CommandType::IF => backend.write_if(p.arg1().unwrap()),
+        parser::CommandType::FUNCTION => {
+            backend.write_function(p.arg1().unwrap(), p.clone().arg2().unwrap())
+        }
+        parser::CommandType::RETURN => backend.write_return(),
+        parser::CommandType::CALL if tail_calls && next_is_return(p) => {
+            backend.write_tail_call(p.arg1().unwrap(), p.clone().arg2().unwrap());
+            // The return is replaced by the tail call, so skip over it
+            p.advance();
+        }
+        parser::CommandType::CALL => {
+            backend.write_call(p.arg1().unwrap(), p.clone().arg2().unwrap())
+        }
+        parser::CommandType::ASM => backend.write_asm(&p.asm_block),
+    };
+}
+
+/// Is the command after the current one a `return`?
+fn next_is_return(p: &parser::Parser<'_>) -> bool {
+    let mut next = p.clone();
+    if !next.has_more_lines() {
+        return false;
+    }
+    next.advance();
+    matches!(next.command_type(), parser::CommandType::RETURN)
+}
+
+/// Translate sources given as filename and contents, like translate() does without its options.
+#[cfg(test)]
+fn translate_sources(
+    backend: &mut dyn Backend,
+    sources: &[(&str, &str)],
+    init_function: Option<&str>,
+) {
+    translate_sources_with(backend, sources, init_function, false);
+}
+
+/// Translate sources like translate_sources(), with or without `--tail-calls`.
+#[cfg(test)]
+fn translate_sources_with(
+    backend: &mut dyn Backend,
+    sources: &[(&str, &str)],
+    init_function: Option<&str>,
+    tail_calls: bool,
+) {
+    backend.write_prologue(init_function);
+    for (filename, contents) in sources {
+        backend.set_filename(filename);
+        let p = parser::Parser::new(contents, true);
+        parse_file(p, backend, &SourceMap::identity(filename), tail_calls);
+    }
+    backend.write_epilogue();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code_writer::OptimizeFor;
+    use crate::emulator::{self, RunOptions, Stop};
+    use std::process::Command;
+
+    /// A Sys.init trying the extended arithmetic commands on awkward values.
+    ///
+    /// The results are left from RAM[3000] on, in the order of EXTENDED_OPS_RESULTS.
+    pub const EXTENDED_OPS: &str = "function Sys.init 0\npush constant 3000\npop pointer 1\n\
+        push constant 7\npush constant 3\nneg\nmul\npop that 0\n\
+        push constant 300\npush constant 300\nmul\npop that 1\n\
+        push constant 7\nneg\npush constant 2\ndiv\npop that 2\n\
+        push constant 7\npush constant 0\ndiv\npop that 3\n\
+        push constant 7\nneg\npush constant 2\nmod\npop that 4\n\
+        push constant 7\npush constant 0\nmod\npop that 5\n\
+        push constant 1\npush constant 15\nshl\npop that 6\n\
+        push constant 1\nneg\npush constant 1\nshr\npop that 7\n\
+        push constant 1\npush constant 16\nshl\npop that 8\n\
+        label END\ngoto END\n";
+
+    /// What EXTENDED_OPS leaves in RAM[3000] on, the same on every target.
+    ///
+    /// Products wrap to 16 bits, division truncates toward 0, dividing by 0 gives
+    /// 0 and its remainder is the dividend, and shifts are logical and give 0
+    /// when shifting by 16 or more.
+    const EXTENDED_OPS_RESULTS: [i16; 9] = [-21, 24464, -3, 0, -1, 7, -32768, 32767, 0];
+
+    /// A path in the temp folder to write a backend's output to.
+    fn out_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        String::from(path.to_str().unwrap())
+    }
+
+    /// Run a command, or None if its program isn't installed.
+    fn run(program: &str, args: &[&str]) -> Option<Vec<u8>> {
+        let output = Command::new(program).args(args).output().ok()?;
+        assert!(output.status.success(), "{program} {args:?} failed");
+        Some(output.stdout)
+    }
+
+    fn hack_results(optimize: OptimizeFor) -> Vec<i16> {
+        let mut backend = HackBackend::new(optimize, false, Vec::new(), None);
+        translate_sources(&mut backend, &[("Sys", EXTENDED_OPS)], Some("Sys.init"));
+        let options = RunOptions {
+            max_steps: 100_000,
+            sanitize: true,
+            ram: Vec::new(),
+            show: Vec::new(),
         };
+        let outcome = emulator::emulate(backend.chunks(), &options);
+        assert_eq!(outcome.stop, Stop::Halted);
+        outcome.ram[3000..3009]
+            .iter()
+            .map(|value| *value as i16)
+            .collect()
+    }
+
+    fn c_results() -> Option<Vec<i16>> {
+        let mut writer = CWriter::new();
+        translate_sources(&mut writer, &[("Sys", EXTENDED_OPS)], Some("Sys.init"));
+        let path = out_path("c-extended");
+        writer.write_output(&path);
+        let compiled = run("cc", &["-O2", "-o", &path, &format!("{path}.c")]);
+        fs::remove_file(format!("{path}.c")).unwrap();
+        compiled?;
+        let addresses: Vec<String> = (3000..3009).map(|address| address.to_string()).collect();
+        let addresses: Vec<&str> = addresses.iter().map(String::as_str).collect();
+        let output = run(&path, &addresses);
+        fs::remove_file(&path).unwrap();
+        let results = String::from_utf8(output.unwrap())
+            .unwrap()
+            .lines()
+            .map(|line| line.split(" = ").nth(1).unwrap().parse().unwrap())
+            .collect();
+        Some(results)
+    }
+
+    fn x86_results() -> Option<Vec<i16>> {
+        if !cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            return None;
+        }
+        let mut writer = X86Writer::new();
+        translate_sources(&mut writer, &[("Sys", EXTENDED_OPS)], Some("Sys.init"));
+        let path = out_path("x86-extended");
+        writer.write_output(&path);
+        let assembled = run("as", &["-o", &format!("{path}.o"), &format!("{path}.s")]);
+        fs::remove_file(format!("{path}.s")).unwrap();
+        assembled?;
+        let linked = run("ld", &["-o", &path, &format!("{path}.o")]);
+        fs::remove_file(format!("{path}.o")).unwrap();
+        linked?;
+        // Any argument has the program write out RAM
+        let output = run(&path, &["ram"]);
+        fs::remove_file(&path).unwrap();
+        let results = output.unwrap()[6000..6018]
+            .chunks(2)
+            .map(|word| i16::from_le_bytes([word[0], word[1]]))
+            .collect();
+        Some(results)
+    }
+
+    /// There's no wat assembler to run the module with, so this checks its
+    /// helpers have the same edge cases as the other targets.
+    fn check_wat() {
+        let mut writer = WatWriter::new();
+        translate_sources(&mut writer, &[("Sys", EXTENDED_OPS)], Some("Sys.init"));
+        let path = out_path("wat-extended");
+        writer.write_output(&path);
+        let module = fs::read_to_string(format!("{path}.wat")).unwrap();
+        fs::remove_file(format!("{path}.wat")).unwrap();
+        let helper = |name: &str| {
+            let start = module.find(&format!("(func ${name} ")).unwrap();
+            let end = module[start..].find("end)").unwrap();
+            module[start..start + end]
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert!(helper("div").contains("i32.eqz if (result i32) i32.const 0 else"));
+        assert!(helper("mod").contains("i32.eqz if (result i32) local.get $x else"));
+        for shift in ["shl", "shr"] {
+            assert!(helper(shift).contains("i32.const 15 i32.gt_u if (result i32) i32.const 0"));
+        }
+        assert!(helper("shr").contains("i32.const 65535 i32.and local.get $y i32.shr_u"));
+        for op in [
+            "i32.mul",
+            "call $div",
+            "call $mod",
+            "call $shl",
+            "call $shr",
+        ] {
+            assert!(module.contains(op), "{op}");
+        }
+    }
+
+    #[test]
+    fn extended_ops_give_the_same_results_on_every_target() {
+        assert_eq!(hack_results(OptimizeFor::Speed), EXTENDED_OPS_RESULTS);
+        assert_eq!(hack_results(OptimizeFor::Size), EXTENDED_OPS_RESULTS);
+        // Targets whose tools aren't installed are skipped
+        for (target, results) in [("c", c_results()), ("x86_64", x86_results())] {
+            if let Some(results) = results {
+                assert_eq!(results, EXTENDED_OPS_RESULTS, "{target}");
+            }
+        }
+        check_wat();
+    }
+
+    #[test]
+    fn hack_only_options_need_the_hack_target() {
+        for option in ["--checked", "--run", "--listing", "--intrinsics"] {
+            for target in ["c", "wat", "x86_64"] {
+                let cli =
+                    Cli::parse_from(["vm-translator-rust", option, "--target", target, "Main.vm"]);
+                let error = check_usage(&cli).unwrap_err();
+                assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+                assert!(error.to_string().contains(option));
+            }
+            let cli = Cli::parse_from(["vm-translator-rust", option, "Main.vm"]);
+            assert!(check_usage(&cli).is_ok());
+        }
+        let cli = Cli::parse_from(["vm-translator-rust", "--target", "c", "Main.vm"]);
+        assert!(check_usage(&cli).is_ok());
+    }
+
+    #[test]
+    fn watch_translates_again_when_an_input_changes() {
+        let folder = PathBuf::from(out_path("watch"));
+        fs::create_dir_all(folder.join("lib")).unwrap();
+        fs::write(folder.join("Main.vm"), "push constant 1\n").unwrap();
+        fs::write(folder.join("notes.txt"), "").unwrap();
+        let touch = |file: &str| {
+            let later = SystemTime::now() + std::time::Duration::from_secs(60);
+            File::options()
+                .write(true)
+                .open(folder.join(file))
+                .unwrap()
+                .set_modified(later)
+                .unwrap();
+        };
+
+        let mut last = changed_inputs(&folder, false, None).unwrap();
+        assert!(changed_inputs(&folder, false, Some(&last)).is_none());
+        // Files that aren't inputs don't count
+        touch("notes.txt");
+        fs::write(folder.join("lib/Lib.vm"), "").unwrap();
+        assert!(changed_inputs(&folder, false, Some(&last)).is_none());
+        // .vm files compiled from a .jack file don't either, but the .jack file does
+        fs::write(folder.join("Game.jack"), "").unwrap();
+        last = changed_inputs(&folder, false, Some(&last)).unwrap();
+        fs::write(folder.join("Game.vm"), "").unwrap();
+        assert!(changed_inputs(&folder, false, Some(&last)).is_none());
+        touch("Main.vm");
+        last = changed_inputs(&folder, false, Some(&last)).unwrap();
+        fs::remove_file(folder.join("Game.jack")).unwrap();
+        assert!(changed_inputs(&folder, false, Some(&last)).is_some());
+
+        // With --preprocess, a .vm file anywhere under the folder could be included
+        let last = changed_inputs(&folder, true, None).unwrap();
+        touch("lib/Lib.vm");
+        assert!(changed_inputs(&folder, true, Some(&last)).is_some());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn asm_blocks_need_the_hack_target() {
+        let path = format!("{}.vm", out_path("asm-block"));
+        fs::write(&path, "function Sys.init 0\nasm\n@SP\nendasm\nreturn\n").unwrap();
+        let cli = Cli::parse_from(["vm-translator-rust", "--target", "c", &path]);
+        let translated = translate(&cli);
+        fs::remove_file(&path).unwrap();
+        assert!(!translated);
+        assert!(!Path::new(&path.replace(".vm", ".c")).exists());
     }
-    out_file
 }
@@ -0,0 +1,95 @@
+#![allow(clippy::pedantic)]
+
+use log::info;
+use std::collections::HashSet;
+
+/// The Jack OS classes, compiled from the .jack files next to them.
+const CLASSES: [(&str, &str); 8] = [
+    ("Array", include_str!("../os/Array.vm")),
+    ("Keyboard", include_str!("../os/Keyboard.vm")),
+    ("Math", include_str!("../os/Math.vm")),
+    ("Memory", include_str!("../os/Memory.vm")),
+    ("Output", include_str!("../os/Output.vm")),
+    ("Screen", include_str!("../os/Screen.vm")),
+    ("String", include_str!("../os/String.vm")),
+    ("Sys", include_str!("../os/Sys.vm")),
+];
+
+/// Add the OS classes that are called but not defined by the program's sources.
+///
+/// A class counts as defined if any source has a function in it, so the
+/// program's own version of an OS class always wins. `entry` is the function
+/// the bootstrap calls, if there is one. Returns the classes that were added.
+pub fn link(sources: &mut Vec<(String, String)>, entry: Option<&str>) -> Vec<String> {
+    let mut linked = Vec::new();
+    let mut defined: HashSet<String> = HashSet::new();
+    let mut called: HashSet<String> = HashSet::new();
+    if let Some(entry) = entry {
+        called.insert(class_of(entry));
+    }
+    let mut scanned = 0;
+    loop {
+        // Only the sources added since last time need scanning
+        for (_, contents) in &sources[scanned..] {
+            for line in contents.lines() {
+                let mut tokens = line.split("//").next().unwrap().split_whitespace();
+                match (tokens.next(), tokens.next()) {
+                    (Some("function"), Some(function)) => defined.insert(class_of(function)),
+                    (Some("call"), Some(function)) => called.insert(class_of(function)),
+                    _ => false,
+                };
+            }
+        }
+        scanned = sources.len();
+        let missing: Vec<(&str, &str)> = CLASSES
+            .into_iter()
+            .filter(|(class, _)| called.contains(*class) && !defined.contains(*class))
+            .collect();
+        if missing.is_empty() {
+            return linked;
+        }
+        for (class, code) in missing {
+            info!("Linking OS class {class}");
+            sources.push((String::from(class), String::from(code)));
+            linked.push(String::from(class));
+        }
+    }
+}
+
+/// Whether a bundled OS class has a function, so calls to it get linked.
+pub fn defines(function: &str) -> bool {
+    let class = class_of(function);
+    CLASSES
+        .iter()
+        .filter(|(name, _)| *name == class)
+        .any(|(_, code)| {
+            code.lines().any(|line| {
+                let mut tokens = line.split_whitespace();
+                tokens.next() == Some("function") && tokens.next() == Some(function)
+            })
+        })
+}
+
+/// The class part of a function name like `Class.function`.
+fn class_of(function: &str) -> String {
+    String::from(function.split('.').next().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_what_is_called_and_not_defined() {
+        let main = "function Main.main 0\ncall Math.abs 1\ncall Screen.drawPixel 2\nreturn\n\
+                    function Screen.drawPixel 0\npush constant 0\nreturn\n";
+        let mut sources = vec![(String::from("Main"), String::from(main))];
+        let linked = link(&mut sources, Some("Sys.init"));
+        // Sys.init calls the other classes' inits, and Screen is the program's own
+        assert!(linked.contains(&String::from("Sys")));
+        assert!(linked.contains(&String::from("Math")));
+        assert!(!linked.contains(&String::from("Screen")));
+        assert_eq!(sources.len(), linked.len() + 1);
+        assert!(link(&mut sources, Some("Sys.init")).is_empty());
+    }
+}
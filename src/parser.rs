@@ -3,7 +3,7 @@
 use log::info;
 use std::str::Lines;
 
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum CommandType {
     ARITHMETIC,
@@ -15,31 +15,41 @@ pub enum CommandType {
     FUNCTION,
     RETURN,
     CALL,
+    /// A block of Hack assembly between `asm` and `endasm`. Not standard VM.
+    ASM,
 }
 
 #[derive(Clone)]
 pub struct Parser<'a> {
+    /// Line number of the current command.
     pub current_line: u16,
+    /// How many lines have been read, which is past the current command after an asm block.
+    lines_read: u16,
     source_iterator: Lines<'a>,
     pub current_command: String,
+    /// The lines between `asm` and `endasm` when the current command is an asm block.
+    pub asm_block: Vec<String>,
+    /// Accept mul, div, mod, shl and shr as arithmetic commands.
+    extended_ops: bool,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(input: &'a String) -> Self {
+    pub fn new(input: &'a str, extended_ops: bool) -> Self {
         Parser {
             current_line: 0,
+            lines_read: 0,
             source_iterator: input.lines(),
             current_command: String::from(""),
+            asm_block: Vec::new(),
+            extended_ops,
         }
     }
 
     /// Are there more lines in the input?
+    ///
+    /// Blank lines and comments don't count, since advance() skips them.
     pub fn has_more_lines(&self) -> bool {
-        if let Some(_) = self.source_iterator.clone().peekable().peek() {
-            true
-        } else {
-            false
-        }
+        self.source_iterator.clone().any(is_command)
     }
 
     /// Reads the next command from the input and makes it the current command.
@@ -49,43 +59,41 @@ impl<'a> Parser<'a> {
         if !self.has_more_lines() {
             return;
         }
-        match self.source_iterator.next() {
-            Some(line) => match line.split_whitespace().next() {
-                Some(command) => {
-                    //TODO: Skip over blanks and comments.
-                    match command.chars().next() {
-                        Some(c) => {
-                            match c {
-                                '/' => {
-                                    if line.chars().next() == Some('/') {
-                                        // Do nothing to skip over comments
-                                        self.advance()
-                                    }
-                                }
-                                _ => {
-                                    info!("{}", line);
-                                    self.current_command = String::from(line)
-                                }
-                            }
-                        }
-                        None => {}
-                    }
+        for line in self.source_iterator.by_ref() {
+            self.lines_read += 1;
+            // Skip over blanks and comments.
+            if is_command(line) {
+                self.current_line = self.lines_read;
+                info!("{}: {}", self.current_line, line);
+                self.current_command = String::from(line);
+                self.asm_block.clear();
+                if line.split_whitespace().next() == Some("asm") {
+                    self.read_asm_block();
                 }
-                None => {
-                    // Skip blank lines
-                    self.advance()
-                }
-            },
-            None => {
-                self.current_command = String::from("");
-                println!("No Match!");
+                return;
+            }
+        }
+    }
+
+    /// Capture the lines of an asm block verbatim, up to its `endasm`.
+    fn read_asm_block(&mut self) {
+        for line in self.source_iterator.by_ref() {
+            self.lines_read += 1;
+            if line.split_whitespace().next() == Some("endasm") {
+                return;
             }
+            self.asm_block.push(String::from(line));
         }
+        panic!(
+            "asm block starting on line {} has no endasm!",
+            self.current_line
+        );
     }
 
     /// Returns a representation of the current command.
     ///
-    /// If the current command is an arithmetic-logical command, returns C_ARITHMETIC
+    /// If the current command is an arithmetic-logical command, returns C_ARITHMETIC.
+    /// That includes the extended commands when they're enabled.
     pub fn command_type(&self) -> CommandType {
         match self.current_command.split_whitespace().next() {
             None => {
@@ -100,12 +108,24 @@ impl<'a> Parser<'a> {
                 "add" | "sub" | "neg" | "eq" | "gt" | "lt" | "and" | "or" | "not" => {
                     CommandType::ARITHMETIC
                 }
+                "mul" | "div" | "mod" | "shl" | "shr" if self.extended_ops => {
+                    CommandType::ARITHMETIC
+                }
+                "mul" | "div" | "mod" | "shl" | "shr" => panic!(
+                    "{} is an extended arithmetic command, enable it with --extended-ops",
+                    command
+                ),
                 "label" => CommandType::LABEL,
                 "goto" => CommandType::GOTO,
                 "if-goto" => CommandType::IF,
                 "function" => CommandType::FUNCTION,
                 "call" => CommandType::CALL,
                 "return" => CommandType::RETURN,
+                "asm" => CommandType::ASM,
+                _ if command.starts_with('#') => panic!(
+                    "{} is a preprocessor directive, enable it with --preprocess",
+                    command
+                ),
                 _ => panic!("reached unreachable branch of command_type match"),
             },
         }
@@ -119,29 +139,133 @@ impl<'a> Parser<'a> {
     pub fn arg1(&self) -> Option<&str> {
         let mut ret_val = self.current_command.split_whitespace();
         if let CommandType::RETURN = self.command_type() {
-            return None;
+            None
         } else if let CommandType::ARITHMETIC = self.command_type() {
-            return ret_val.next();
+            ret_val.next()
         } else {
-            return self.current_command.split_whitespace().nth(1);
+            self.current_command.split_whitespace().nth(1)
         }
     }
 
     /// Returns the second argument of the current command.
     ///
+    /// `push constant` takes any 16-bit value, see parse_number(). Segment
+    /// indices and the counts after `function` and `call` are plain decimal, see
+    /// parse_index().
+    ///
     /// Exits early if command is not C_PUSH, C_POP, C_FUNCTION, or C_CALL
     pub fn arg2(self) -> Option<i16> {
         match self.command_type() {
             CommandType::PUSH | CommandType::POP | CommandType::FUNCTION | CommandType::CALL => {
-                return Some(
-                    self.current_command
-                        .split_whitespace()
-                        .nth(2)?
-                        .parse::<i16>()
-                        .unwrap(),
-                )
+                let token = self.current_command.split_whitespace().nth(2)?;
+                if matches!(self.command_type(), CommandType::PUSH)
+                    && self.arg1() == Some("constant")
+                {
+                    Some(parse_number(token))
+                } else {
+                    Some(parse_index(token))
+                }
             }
-            _ => return None,
+            _ => None,
         }
     }
 }
+
+/// Is there a command on this line, rather than just a comment or whitespace?
+fn is_command(line: &str) -> bool {
+    match line.split_whitespace().next() {
+        Some(token) => !token.starts_with("//"),
+        None => false,
+    }
+}
+
+/// Parse a 16-bit number, written as signed or unsigned decimal or as hex.
+///
+/// Values from 32768 up wrap around to negative, like they do in Hack RAM,
+/// so `65535`, `0xFFFF` and `-1` are all the same number.
+pub fn parse_number(token: &str) -> i16 {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let value = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => digits.parse::<i32>(),
+    };
+    match value {
+        Ok(value) if negative && value <= 32768 => (-value) as i16,
+        Ok(value) if !negative && value <= 65535 => value as u16 as i16,
+        _ => panic!("{:?} isn't a 16-bit number!", token),
+    }
+}
+
+/// Parse a segment index or a count of arguments or locals.
+///
+/// These are plain decimal from 0 to 32767; only `push constant` takes the
+/// other ways of writing a number.
+pub fn parse_index(token: &str) -> i16 {
+    match index(token) {
+        Some(index) => index,
+        None => panic!("{:?} isn't a decimal number from 0 to 32767!", token),
+    }
+}
+
+/// Like parse_index(), but None for anything it would refuse.
+pub fn index(token: &str) -> Option<i16> {
+    if token.bytes().all(|byte| byte.is_ascii_digit()) {
+        token.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_wrap_to_16_bits() {
+        assert_eq!(parse_number("17"), 17);
+        assert_eq!(parse_number("65535"), -1);
+        assert_eq!(parse_number("0xFFFF"), -1);
+        assert_eq!(parse_number("-1"), -1);
+        assert_eq!(parse_number("-32768"), i16::MIN);
+        assert_eq!(parse_number("0x8000"), i16::MIN);
+    }
+
+    #[test]
+    fn only_push_constant_takes_wide_numbers() {
+        let mut p = Parser::new(
+            "push constant 0xFFFF\npush local 12\ncall Main.f 2\n",
+            false,
+        );
+        p.advance();
+        assert_eq!(p.clone().arg2(), Some(-1));
+        p.advance();
+        assert_eq!(p.clone().arg2(), Some(12));
+        p.advance();
+        assert_eq!(p.clone().arg2(), Some(2));
+        assert_eq!(index("007"), Some(7));
+        assert_eq!(index("32767"), Some(32767));
+        for token in ["-1", "+1", "0x1", "32768", ""] {
+            assert_eq!(index(token), None);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "\"0x10\" isn't a decimal number from 0 to 32767")]
+    fn hex_indices_are_refused() {
+        let mut p = Parser::new("push local 0x10\n", false);
+        p.advance();
+        p.arg2();
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a 16-bit number")]
+    fn numbers_past_16_bits_are_refused() {
+        parse_number("65536");
+    }
+}
@@ -0,0 +1,279 @@
+#![allow(clippy::pedantic)]
+
+use log::info;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Where each line of a source came from, so messages can point at what was written.
+#[derive(Clone)]
+pub struct SourceMap {
+    file: String,
+    /// File and line for each line of the preprocessed code. Empty when nothing moved.
+    lines: Vec<(String, u16)>,
+}
+
+impl SourceMap {
+    /// Map for a source that wasn't preprocessed, where every line is where it was.
+    pub fn identity(filename: &str) -> Self {
+        SourceMap {
+            file: format!("{filename}.vm"),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Map for code generated from another file, with the file and line of each line of code.
+    pub fn new(file: &str, lines: Vec<(String, u16)>) -> Self {
+        SourceMap {
+            file: String::from(file),
+            lines,
+        }
+    }
+
+    /// Map for code rewritten from this code, given the line each of its lines came from.
+    pub fn remap(&self, lines: &[u16]) -> Self {
+        SourceMap {
+            file: self.file.clone(),
+            lines: lines
+                .iter()
+                .map(|line| {
+                    let (file, line) = self.locate(*line);
+                    (String::from(file), line)
+                })
+                .collect(),
+        }
+    }
+
+    /// The original file and line for a line of the code, counting from 1.
+    pub fn locate(&self, line: u16) -> (&str, u16) {
+        match self.lines.get((line as usize).wrapping_sub(1)) {
+            Some((file, line)) => (file, *line),
+            None => (&self.file, line),
+        }
+    }
+}
+
+/// A `#macro` definition.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+struct Preprocessor<'a> {
+    /// Folder that file names are relative to.
+    base: &'a Path,
+    defines: HashMap<String, String>,
+    macros: HashMap<String, Macro>,
+    /// How many macros have been expanded, used to make labels in them unique.
+    expansions: usize,
+    /// Files being included right now, to catch includes that loop.
+    including: Vec<String>,
+    /// Macros being expanded right now, to catch macros that expand themselves.
+    expanding: Vec<String>,
+    text: String,
+    lines: Vec<(String, u16)>,
+}
+
+/// Expand `#include`, `#define` and `#macro` directives in a .vm source.
+///
+/// Returns the expanded code and where each of its lines came from. Lines
+/// from a macro are mapped to where it was used.
+pub fn preprocess(base: &Path, filename: &str, contents: &str) -> (String, SourceMap) {
+    let mut preprocessor = Preprocessor {
+        base,
+        defines: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        including: Vec::new(),
+        expanding: Vec::new(),
+        text: String::new(),
+        lines: Vec::new(),
+    };
+    let file = format!("{filename}.vm");
+    preprocessor.process_file(&file, contents);
+    (preprocessor.text, SourceMap::new(&file, preprocessor.lines))
+}
+
+impl Preprocessor<'_> {
+    fn process_file(&mut self, file: &str, contents: &str) {
+        self.including.push(String::from(file));
+        let mut lines = contents.lines().zip(1..);
+        while let Some((line, number)) = lines.next() {
+            let at = |message: String| format!("{file}:{number}: {message}");
+            let mut tokens = code(line).split_whitespace();
+            match tokens.next() {
+                Some("#include") => {
+                    let name = code(line)
+                        .trim()
+                        .trim_start_matches("#include")
+                        .trim()
+                        .strip_prefix('"')
+                        .and_then(|name| name.strip_suffix('"'))
+                        .unwrap_or_else(|| {
+                            panic!("{}", at(String::from("expected #include \"file.vm\"")))
+                        });
+                    // Included files are found next to the file including them
+                    let included = Path::new(file).parent().unwrap().join(name);
+                    let included = included.to_str().unwrap();
+                    if self.including.iter().any(|file| file == included) {
+                        panic!("{}", at(format!("{included} includes itself")));
+                    }
+                    let contents = fs::read_to_string(self.base.join(included))
+                        .unwrap_or_else(|_| panic!("{}", at(format!("can't read {included}"))));
+                    info!("{file}:{number}: including {included}");
+                    self.process_file(included, &contents);
+                }
+                Some("#define") => {
+                    let name = tokens
+                        .next()
+                        .unwrap_or_else(|| panic!("{}", at(String::from("#define needs a name"))));
+                    let value = substitute(&tokens.collect::<Vec<_>>().join(" "), &self.defines);
+                    if value.is_empty() {
+                        panic!("{}", at(format!("#define {name} needs a value")));
+                    }
+                    self.defines.insert(String::from(name), value);
+                }
+                Some("#macro") => {
+                    let name = tokens
+                        .next()
+                        .unwrap_or_else(|| panic!("{}", at(String::from("#macro needs a name"))));
+                    let params = tokens.map(String::from).collect();
+                    let mut body = Vec::new();
+                    loop {
+                        let Some((line, _)) = lines.next() else {
+                            panic!("{}", at(format!("#macro {name} has no #endmacro")));
+                        };
+                        match code(line).split_whitespace().next() {
+                            Some("#endmacro") => break,
+                            Some(directive) if directive.starts_with('#') => panic!(
+                                "{}",
+                                at(format!("{directive} can't be used inside #macro {name}"))
+                            ),
+                            _ => body.push(String::from(line)),
+                        }
+                    }
+                    self.macros
+                        .insert(String::from(name), Macro { params, body });
+                }
+                Some(directive) if directive.starts_with('#') => {
+                    panic!("{}", at(format!("unknown directive {directive}")))
+                }
+                _ => self.expand_line(line, &(String::from(file), number)),
+            }
+        }
+        self.including.pop();
+    }
+
+    /// Substitute the defines in a line, and expand it if it uses a macro.
+    fn expand_line(&mut self, line: &str, origin: &(String, u16)) {
+        let line = substitute(line, &self.defines);
+        let mut tokens = code(&line).split_whitespace();
+        let Some(name) = tokens.next().filter(|name| self.macros.contains_key(*name)) else {
+            self.text.push_str(&line);
+            self.text.push('\n');
+            self.lines.push(origin.clone());
+            return;
+        };
+        let at = |message: String| format!("{}:{}: {message}", origin.0, origin.1);
+        if self.expanding.iter().any(|expanding| expanding == name) {
+            panic!("{}", at(format!("macro {name} expands itself")));
+        }
+        let args: Vec<&str> = tokens.collect();
+        let params = &self.macros[name].params;
+        if args.len() != params.len() {
+            panic!(
+                "{}",
+                at(format!(
+                    "macro {name} takes {} arguments, not {}",
+                    params.len(),
+                    args.len()
+                ))
+            );
+        }
+        let bindings: HashMap<String, String> = params
+            .iter()
+            .cloned()
+            .zip(args.iter().map(|arg| String::from(*arg)))
+            .collect();
+        self.expansions += 1;
+        let body: Vec<String> = self.macros[name]
+            .body
+            .iter()
+            .map(|line| substitute(line, &bindings).replace('%', &self.expansions.to_string()))
+            .collect();
+        self.expanding.push(String::from(name));
+        for line in body {
+            self.expand_line(&line, origin);
+        }
+        self.expanding.pop();
+    }
+}
+
+/// The part of a line before any comment.
+fn code(line: &str) -> &str {
+    line.split("//").next().unwrap()
+}
+
+/// Replace whole tokens with their values, leaving comments alone.
+fn substitute(line: &str, values: &HashMap<String, String>) -> String {
+    let code = code(line);
+    if !code
+        .split_whitespace()
+        .any(|token| values.contains_key(token))
+    {
+        return String::from(line);
+    }
+    let mut substituted = code
+        .split_whitespace()
+        .map(|token| values.get(token).map_or(token, String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if code.len() < line.len() {
+        substituted.push(' ');
+        substituted.push_str(&line[code.len()..]);
+    }
+    substituted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_defines_and_macros_keeping_their_lines() {
+        let source = "#define LIMIT 10\n#macro INC segment index\npush segment index\n\
+                      push constant 1\nadd\npop segment index\nlabel SKIP%\n#endmacro\n\
+                      function Main.main 0\nINC local 0\nINC local 0\npush constant LIMIT\n\
+                      return\n";
+        let (text, map) = preprocess(Path::new("."), "Main", source);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[1..6],
+            [
+                "push local 0",
+                "push constant 1",
+                "add",
+                "pop local 0",
+                "label SKIP1"
+            ]
+        );
+        assert_eq!(lines[10], "label SKIP2");
+        assert_eq!(lines[11], "push constant 10");
+        // Macro code is mapped to where it was used
+        assert_eq!(map.locate(1), ("Main.vm", 9));
+        assert_eq!(map.locate(2), ("Main.vm", 10));
+        assert_eq!(map.locate(7), ("Main.vm", 11));
+        assert_eq!(map.locate(12), ("Main.vm", 12));
+    }
+
+    #[test]
+    fn remap_follows_the_lines_to_where_they_were_written() {
+        let map = SourceMap::new(
+            "Main.vm",
+            vec![(String::from("a.vm"), 5), (String::from("b.vm"), 7)],
+        );
+        let remapped = map.remap(&[2, 2, 1]);
+        assert_eq!(remapped.locate(1), ("b.vm", 7));
+        assert_eq!(remapped.locate(3), ("a.vm", 5));
+    }
+}
@@ -0,0 +1,100 @@
+#![allow(clippy::pedantic)]
+
+use crate::parser::{self, CommandType, Parser};
+use crate::preprocessor::SourceMap;
+
+/// Find everything in a .vm file that isn't standard Nand2Tetris VM code.
+///
+/// Returns one message per problem, starting with the file and line it was written on.
+pub fn non_standard(contents: &str, map: &SourceMap) -> Vec<String> {
+    problems(contents)
+        .into_iter()
+        .map(|(line, problem)| {
+            let (file, line) = map.locate(line);
+            format!("{file}:{line}: {problem}")
+        })
+        .collect()
+}
+
+/// Find the asm blocks in a .vm file, for targets that can't translate them.
+///
+/// Returns one message per block, like non_standard().
+pub fn asm_blocks(contents: &str, map: &SourceMap) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut p = Parser::new(contents, true);
+    while p.has_more_lines() {
+        p.advance();
+        if matches!(p.command_type(), CommandType::ASM) {
+            let (file, line) = map.locate(p.current_line);
+            blocks.push(format!(
+                "{file}:{line}: asm blocks only work with --target hack"
+            ));
+        }
+    }
+    blocks
+}
+
+/// The line of each non-standard command in a .vm file, with what's wrong with it.
+pub fn problems(contents: &str) -> Vec<(u16, String)> {
+    let mut problems = Vec::new();
+    let mut p = Parser::new(contents, true);
+    while p.has_more_lines() {
+        p.advance();
+        if let Some(problem) = problem(&p) {
+            problems.push((p.current_line, problem));
+        }
+    }
+    problems
+}
+
+/// What's non-standard about the parser's current command, if anything.
+pub fn problem(p: &Parser<'_>) -> Option<String> {
+    match p.command_type() {
+        CommandType::ASM => Some(String::from("asm blocks aren't standard VM")),
+        CommandType::ARITHMETIC => match p.arg1().unwrap() {
+            op @ ("mul" | "div" | "mod" | "shl" | "shr") => Some(format!(
+                "{op} is an extended arithmetic command, not standard VM"
+            )),
+            _ => None,
+        },
+        CommandType::PUSH if p.arg1() == Some("constant") => {
+            let value = p.current_command.split_whitespace().nth(2).unwrap_or("");
+            if parser::index(value).is_some() {
+                None
+            } else {
+                Some(format!(
+                    "standard VM constants are decimal from 0 to 32767, not {value}"
+                ))
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_what_isnt_standard() {
+        let code = "push constant 7\npush constant 0x10\nmul\nasm\n@SP\nendasm\n\
+                    push constant 32768\nadd\n";
+        let lines: Vec<u16> = problems(code).iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [2, 3, 4, 7]);
+        let map = SourceMap::new("Main.vm", vec![(String::from("lib.vm"), 40); 8]);
+        assert!(non_standard(code, &map)[0].starts_with("lib.vm:40: standard VM constants"));
+    }
+
+    #[test]
+    fn finds_asm_blocks() {
+        let code = "push constant 7\nasm\n@SP\nendasm\nadd\nasm\nendasm\n";
+        let blocks = asm_blocks(code, &SourceMap::identity("Main"));
+        assert_eq!(
+            blocks,
+            [
+                "Main.vm:2: asm blocks only work with --target hack",
+                "Main.vm:6: asm blocks only work with --target hack"
+            ]
+        );
+    }
+}
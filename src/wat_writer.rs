@@ -0,0 +1,522 @@
+#![allow(clippy::pedantic)]
+
+use crate::backend::Backend;
+use crate::listing::Origin;
+use crate::parser::CommandType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use indoc::formatdoc;
+
+/// RAM address of the first static variable, as handed out by the Hack assembler.
+const FIRST_STATIC: u16 = 16;
+
+/// Marks a jump target in the generated code until case numbers are known.
+const TARGET_START: &str = "@@target:";
+const TARGET_END: &str = "@@";
+
+/// A run of code that can be jumped to.
+struct Case {
+    name: String,
+    code: String,
+}
+
+/// Translates to a WebAssembly text module that models Hack RAM.
+///
+/// WebAssembly has no goto, so the program is split into cases at every
+/// function, label and return address. A loop around a `br_table` jumps
+/// between them using a program counter, and code falls through from one
+/// case into the next just like it does on Hack.
+pub struct WatWriter {
+    filename: String,
+    current_function: String,
+    cases: Vec<Case>,
+    call_counter: u16,
+    static_lookup: HashMap<String, u16>,
+    /// The label written by the last command, for spotting `label X; goto X`.
+    previous_label: Option<String>,
+}
+
+impl WatWriter {
+    pub fn new() -> Self {
+        WatWriter {
+            filename: String::from("Sys"),
+            current_function: String::from("bootstrap"),
+            cases: vec![Case {
+                name: String::from("start"),
+                code: String::new(),
+            }],
+            call_counter: 0,
+            static_lookup: HashMap::new(),
+            previous_label: None,
+        }
+    }
+
+    /// Labels are scoped to the file and function they're declared in.
+    fn scoped_label(&self, label: &str) -> String {
+        format!("label {}.{}${label}", self.filename, self.current_function)
+    }
+
+    /// Statics get consecutive RAM addresses in the order they're first used.
+    fn static_address(&mut self, index: i16) -> u16 {
+        let next = FIRST_STATIC + self.static_lookup.len() as u16;
+        *self
+            .static_lookup
+            .entry(format!("{}.{index}", self.filename))
+            .or_insert(next)
+    }
+
+    /// Code leaving the RAM address of a memory segment cell on the wasm stack.
+    fn segment_address(&mut self, segment: &str, index: i16) -> String {
+        let base = match segment {
+            "local" => 1,
+            "argument" => 2,
+            "this" => 3,
+            "that" => 4,
+            "pointer" => {
+                if index != 0 && index != 1 {
+                    panic!("pointer can only be 0 or 1!");
+                }
+                return format!("i32.const {}", 3 + index);
+            }
+            "temp" => return format!("i32.const {}", 5 + index),
+            "static" => return format!("i32.const {}", self.static_address(index)),
+            _ => panic!("Unknown segment {:?}!", segment),
+        };
+        format!("i32.const {base}\ncall $load\ni32.const {index}\ni32.add")
+    }
+
+    fn push_code(&mut self, code: &str) {
+        let case = self.cases.last_mut().unwrap();
+        for line in code.lines() {
+            case.code.push_str("    ");
+            case.code.push_str(line);
+            case.code.push('\n');
+        }
+    }
+
+    /// Start a new case that can be jumped to by name.
+    fn start_case(&mut self, name: String) {
+        self.cases.push(Case {
+            name,
+            code: String::new(),
+        });
+    }
+
+    /// Code that jumps to the named case.
+    fn jump_to(&self, name: &str) -> String {
+        format!("i32.const {TARGET_START}{name}{TARGET_END}\nglobal.set $pc\nbr $dispatch")
+    }
+}
+
+impl Backend for WatWriter {
+    fn write_prologue(&mut self, init_function: Option<&str>) {
+        if let Some(init_function) = init_function {
+            self.push_code(";; bootstrap\ni32.const 0\ni32.const 256\ncall $store");
+            self.write_call(init_function, 0);
+            // Returning from the entry point ends the program
+            self.push_code("br $halt");
+        }
+    }
+
+    fn write_epilogue(&mut self) {
+        self.push_code("br $halt");
+    }
+
+    fn set_filename(&mut self, filename: &str) {
+        self.filename = String::from(filename);
+        self.current_function = String::from("bootstrap");
+    }
+
+    fn set_origin(&mut self, origin: &Origin) {
+        let comment = format!(";; {}:{}: {}", origin.file, origin.line, origin.command);
+        self.push_code(comment.as_str());
+    }
+
+    fn write_arithmetic(&mut self, op: &str) {
+        self.previous_label = None;
+        let code = match op {
+            "add" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.add",
+            "sub" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.sub",
+            "and" => "call $pop\ncall $pop\ni32.and",
+            "or" => "call $pop\ncall $pop\ni32.or",
+            "neg" => "i32.const 0\ncall $pop\ni32.sub",
+            "not" => "call $pop\ni32.const -1\ni32.xor",
+            // Like on Hack, comparisons look at the wrapped difference
+            "eq" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.sub\ncall $wrap\ni32.eqz\ncall $bool",
+            "gt" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.sub\ncall $wrap\ni32.const 0\ni32.gt_s\ncall $bool",
+            "lt" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.sub\ncall $wrap\ni32.const 0\ni32.lt_s\ncall $bool",
+            "mul" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ni32.mul",
+            "div" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ncall $div",
+            "mod" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ncall $mod",
+            "shl" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ncall $shl",
+            "shr" => "call $pop\nlocal.set $y\ncall $pop\nlocal.get $y\ncall $shr",
+            _ => panic!("Tried to do math on a not math ({:?}) thing!", op),
+        };
+        self.push_code(code);
+        self.push_code("call $push");
+    }
+
+    fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: i16) {
+        self.previous_label = None;
+        let code = match command_type {
+            CommandType::PUSH if segment == "constant" => format!("i32.const {index}\ncall $push"),
+            CommandType::PUSH => format!(
+                "{}\ncall $load\ncall $push",
+                self.segment_address(segment, index)
+            ),
+            CommandType::POP if segment == "constant" => panic!("Can't pop constant!"),
+            CommandType::POP => format!(
+                "{}\ncall $pop\ncall $store",
+                self.segment_address(segment, index)
+            ),
+            _ => panic!("Error in matching what command to run in push_pop!"),
+        };
+        self.push_code(code.as_str());
+    }
+
+    fn write_label(&mut self, label: &str) {
+        let name = self.scoped_label(label);
+        self.start_case(name);
+        self.previous_label = Some(String::from(label));
+    }
+
+    fn write_goto(&mut self, label: &str) {
+        // `label X; goto X` spins forever, which is how Hack programs halt
+        let code = if self.previous_label.as_deref() == Some(label) {
+            String::from("br $halt")
+        } else {
+            self.jump_to(self.scoped_label(label).as_str())
+        };
+        self.push_code(code.as_str());
+        self.previous_label = None;
+    }
+
+    fn write_if(&mut self, label: &str) {
+        self.previous_label = None;
+        let code = format!(
+            "call $pop\nif\n{}\nend",
+            self.jump_to(self.scoped_label(label).as_str())
+        );
+        self.push_code(code.as_str());
+    }
+
+    fn write_function(&mut self, function_name: &str, n_vars: i16) {
+        self.previous_label = None;
+        self.start_case(format!("function {function_name}"));
+        for _ in 0..n_vars {
+            self.push_code("i32.const 0\ncall $push");
+        }
+        self.current_function = String::from(function_name.split('.').nth(1).unwrap());
+    }
+
+    fn write_call(&mut self, function_name: &str, n_args: i16) {
+        self.previous_label = None;
+        let return_case = format!("return {}", self.call_counter);
+        self.call_counter += 1;
+        let code = formatdoc! {"
+            i32.const {TARGET_START}{return_case}{TARGET_END}
+            call $push
+            i32.const 1
+            call $load
+            call $push
+            i32.const 2
+            call $load
+            call $push
+            i32.const 3
+            call $load
+            call $push
+            i32.const 4
+            call $load
+            call $push
+            i32.const 2
+            i32.const 0
+            call $load
+            i32.const {}
+            i32.sub
+            call $store
+            i32.const 1
+            i32.const 0
+            call $load
+            call $store
+            {}",
+            5 + n_args,
+            self.jump_to(format!("function {function_name}").as_str())
+        };
+        self.push_code(code.as_str());
+        self.start_case(return_case);
+    }
+
+    fn write_return(&mut self) {
+        self.previous_label = None;
+        self.push_code(
+            formatdoc! {"
+            i32.const 1
+            call $load
+            local.set $frame
+            ;; return address *(frame-5)
+            local.get $frame
+            i32.const 5
+            i32.sub
+            call $load
+            local.set $y
+            ;; *ARG = pop()
+            i32.const 2
+            call $load
+            call $pop
+            call $store
+            ;; SP = ARG+1
+            i32.const 0
+            i32.const 2
+            call $load
+            i32.const 1
+            i32.add
+            call $store
+            ;; restore THAT, THIS, ARG and LCL from *(frame-1)..*(frame-4)
+            i32.const 4
+            local.get $frame
+            i32.const 1
+            i32.sub
+            call $load
+            call $store
+            i32.const 3
+            local.get $frame
+            i32.const 2
+            i32.sub
+            call $load
+            call $store
+            i32.const 2
+            local.get $frame
+            i32.const 3
+            i32.sub
+            call $load
+            call $store
+            i32.const 1
+            local.get $frame
+            i32.const 4
+            i32.sub
+            call $load
+            call $store
+            local.get $y
+            global.set $pc
+            br $dispatch"
+            }
+            .as_str(),
+        );
+    }
+
+    fn write_output(&self, out_path: &str) {
+        let case_numbers: HashMap<&str, usize> = self
+            .cases
+            .iter()
+            .enumerate()
+            .map(|(i, case)| (case.name.as_str(), i))
+            .collect();
+        let halted = self.cases.len();
+
+        // Open one block per case; br_table to block n lands on case n's code
+        let mut body = String::from("    block $halt\n    loop $dispatch\n");
+        body.push_str(
+            "    ;; Run out of budget, leaving $pc to resume from\n    local.get $budget\n    i32.eqz\n    if\n    i32.const 0\n    return\n    end\n    local.get $budget\n    i32.const 1\n    i32.sub\n    local.set $budget\n",
+        );
+        for i in (0..self.cases.len()).rev() {
+            body.push_str(format!("    block $case{i}\n").as_str());
+        }
+        body.push_str("    global.get $pc\n    br_table");
+        for i in 0..self.cases.len() {
+            body.push_str(format!(" $case{i}").as_str());
+        }
+        body.push_str(" $halt\n");
+        for (i, case) in self.cases.iter().enumerate() {
+            body.push_str(format!("    end ;; {}\n", case.name).as_str());
+            body.push_str(resolve_targets(&case.code, &case_numbers).as_str());
+            if i == self.cases.len() - 1 {
+                body.push_str("    br $halt\n");
+            }
+        }
+        body.push_str("    end\n    end\n");
+
+        let module = formatdoc! {r#"
+            ;; Generated by vm-translator-rust.
+            ;;
+            ;; Hack RAM is the exported memory, one little-endian 16-bit word per
+            ;; address, so RAM[n] is at byte 2n. Call run(budget) to execute up to
+            ;; `budget` jumps; it returns 1 once the program halts and 0 if it ran
+            ;; out of budget, in which case calling it again carries on.
+            (module
+              (memory (export "ram") 1)
+              ;; Byte offsets and sizes of the memory-mapped I/O regions
+              (global (export "SCREEN") i32 (i32.const 32768))
+              (global (export "SCREEN_BYTES") i32 (i32.const 16384))
+              (global (export "KBD") i32 (i32.const 49152))
+              (global $pc (mut i32) (i32.const 0))
+
+              ;; Sign-extend the low 16 bits, like Hack's 16-bit arithmetic
+              (func $wrap (param $value i32) (result i32)
+                local.get $value
+                i32.const 16
+                i32.shl
+                i32.const 16
+                i32.shr_s)
+
+              ;; Turn a wasm boolean into Hack's true (-1) or false (0)
+              (func $bool (param $value i32) (result i32)
+                i32.const 0
+                local.get $value
+                i32.sub)
+
+              ;; Dividing by zero gives 0, leaving x as the remainder. Operands are
+              ;; sign-extended, so -32768 / -1 doesn't overflow i32.
+              (func $div (param $x i32) (param $y i32) (result i32)
+                local.get $y
+                i32.eqz
+                if (result i32)
+                  i32.const 0
+                else
+                  local.get $x
+                  local.get $y
+                  i32.div_s
+                end)
+
+              (func $mod (param $x i32) (param $y i32) (result i32)
+                local.get $y
+                i32.eqz
+                if (result i32)
+                  local.get $x
+                else
+                  local.get $x
+                  local.get $y
+                  i32.rem_s
+                end)
+
+              ;; Shifting by anything outside 0..=15 gives 0
+              (func $shl (param $x i32) (param $y i32) (result i32)
+                local.get $y
+                i32.const 65535
+                i32.and
+                i32.const 15
+                i32.gt_u
+                if (result i32)
+                  i32.const 0
+                else
+                  local.get $x
+                  local.get $y
+                  i32.shl
+                end)
+
+              (func $shr (param $x i32) (param $y i32) (result i32)
+                local.get $y
+                i32.const 65535
+                i32.and
+                i32.const 15
+                i32.gt_u
+                if (result i32)
+                  i32.const 0
+                else
+                  local.get $x
+                  i32.const 65535
+                  i32.and
+                  local.get $y
+                  i32.shr_u
+                end)
+
+              (func $load (param $address i32) (result i32)
+                local.get $address
+                i32.const 32767
+                i32.and
+                i32.const 1
+                i32.shl
+                i32.load16_s)
+
+              (func $store (param $address i32) (param $value i32)
+                local.get $address
+                i32.const 32767
+                i32.and
+                i32.const 1
+                i32.shl
+                local.get $value
+                i32.store16)
+
+              (func $push (param $value i32)
+                i32.const 0
+                call $load
+                local.get $value
+                call $store
+                i32.const 0
+                i32.const 0
+                call $load
+                i32.const 1
+                i32.add
+                call $store)
+
+              (func $pop (result i32)
+                i32.const 0
+                i32.const 0
+                call $load
+                i32.const 1
+                i32.sub
+                call $store
+                i32.const 0
+                call $load
+                call $load)
+
+              (func (export "run") (param $budget i32) (result i32)
+                (local $y i32)
+                (local $frame i32)
+            {}
+                ;; Halted, so stay halted
+                i32.const {halted}
+                global.set $pc
+                i32.const 1))
+            "#,
+            body
+        };
+        let mut out_file =
+            File::create(format!("{out_path}.wat")).expect("Unable to create new file");
+        out_file
+            .write_all(module.as_bytes())
+            .expect("Error writing to file");
+    }
+}
+
+/// Replace jump target markers with case numbers.
+fn resolve_targets(code: &str, case_numbers: &HashMap<&str, usize>) -> String {
+    let mut resolved = String::new();
+    let mut rest = code;
+    while let Some(start) = rest.find(TARGET_START) {
+        resolved.push_str(&rest[..start]);
+        let after = &rest[start + TARGET_START.len()..];
+        let end = after.find(TARGET_END).unwrap();
+        let name = &after[..end];
+        match case_numbers.get(name) {
+            Some(number) => resolved.push_str(number.to_string().as_str()),
+            None => panic!("Jump to undefined {}!", name),
+        }
+        rest = &after[end + TARGET_END.len()..];
+    }
+    resolved.push_str(rest);
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_is_not_indented() {
+        let mut writer = WatWriter::new();
+        writer.write_prologue(None);
+        writer.set_filename("Indent");
+        writer.write_push_pop(CommandType::PUSH, "constant", 7);
+        writer.write_epilogue();
+        let out_path = std::env::temp_dir().join(format!("wat-indent-{}", std::process::id()));
+        let out_path = out_path.to_str().unwrap();
+        writer.write_output(out_path);
+        let module = std::fs::read_to_string(format!("{out_path}.wat")).unwrap();
+        std::fs::remove_file(format!("{out_path}.wat")).unwrap();
+        assert!(module.starts_with(";; Generated by vm-translator-rust."));
+        assert!(module.contains("\n(module\n  (memory (export \"ram\") 1)\n"));
+        assert!(module.contains("\n  ;; Dividing by zero gives 0"));
+    }
+}
@@ -0,0 +1,374 @@
+#![allow(clippy::pedantic)]
+
+use crate::backend::Backend;
+use crate::c_writer::c_label;
+use crate::listing::Origin;
+use crate::parser::CommandType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use indoc::formatdoc;
+
+/// RAM address of the first static variable, as handed out by the Hack assembler.
+const FIRST_STATIC: u16 = 16;
+
+/// Translates to x86-64 GNU assembly for Linux.
+///
+/// Hack RAM lives in a BSS array addressed through %rbp, while SP, LCL, ARG,
+/// THIS and THAT are kept in registers and only written back to RAM when the
+/// program halts. Return addresses are small integers pushed on the VM stack
+/// like on Hack, and `return` jumps back through a table.
+pub struct X86Writer {
+    filename: String,
+    current_function: String,
+    code: String,
+    call_counter: u16,
+    static_lookup: HashMap<String, u16>,
+    /// The label written by the last command, for spotting `label X; goto X`.
+    previous_label: Option<String>,
+}
+
+impl X86Writer {
+    pub fn new() -> Self {
+        X86Writer {
+            filename: String::from("Sys"),
+            current_function: String::from("bootstrap"),
+            code: String::new(),
+            call_counter: 0,
+            static_lookup: HashMap::new(),
+            previous_label: None,
+        }
+    }
+
+    /// Labels are scoped to the file and function they're declared in.
+    fn scoped_label(&self, label: &str) -> String {
+        c_label(
+            "L",
+            format!("{}.{}${label}", self.filename, self.current_function).as_str(),
+        )
+    }
+
+    /// Statics get consecutive RAM addresses in the order they're first used.
+    fn static_address(&mut self, index: i16) -> u16 {
+        let next = FIRST_STATIC + self.static_lookup.len() as u16;
+        *self
+            .static_lookup
+            .entry(format!("{}.{index}", self.filename))
+            .or_insert(next)
+    }
+
+    /// Code to point %rsi at the RAM cell of a memory segment, and the operand for it.
+    ///
+    /// The pointer segment is its own operand, since THIS and THAT live in registers.
+    fn segment_operand(&mut self, segment: &str, index: i16) -> (String, String) {
+        let register = match segment {
+            "local" => "%r13",
+            "argument" => "%r14",
+            "this" => "%r15",
+            "that" => "%rbx",
+            "pointer" => {
+                return match index {
+                    0 => (String::new(), String::from("%r15w")),
+                    1 => (String::new(), String::from("%bx")),
+                    _ => panic!("pointer can only be 0 or 1!"),
+                }
+            }
+            "temp" => return (String::new(), format!("{}(%rbp)", 2 * (5 + index))),
+            "static" => {
+                return (
+                    String::new(),
+                    format!("{}(%rbp)", 2 * self.static_address(index)),
+                )
+            }
+            _ => panic!("Unknown segment {:?}!", segment),
+        };
+        (
+            format!("ADDR {register}, {index}\n"),
+            String::from("(%rbp,%rsi,2)"),
+        )
+    }
+
+    fn push_line(&mut self, line: &str) {
+        for line in line.lines() {
+            if !line.ends_with(':') {
+                self.code.push_str("    ");
+            }
+            self.code.push_str(line);
+            self.code.push('\n');
+        }
+    }
+}
+
+impl Backend for X86Writer {
+    fn write_prologue(&mut self, init_function: Option<&str>) {
+        if let Some(init_function) = init_function {
+            self.push_line("# bootstrap");
+            self.push_line("mov $256, %r12d");
+            self.write_call(init_function, 0);
+            // Returning from the entry point ends the program
+            self.push_line("jmp halt");
+        }
+    }
+
+    fn write_epilogue(&mut self) {
+        self.push_line("jmp halt");
+    }
+
+    fn set_filename(&mut self, filename: &str) {
+        self.filename = String::from(filename);
+        self.current_function = String::from("bootstrap");
+    }
+
+    fn set_origin(&mut self, origin: &Origin) {
+        let comment = format!("# {}:{}: {}", origin.file, origin.line, origin.command);
+        self.push_line(comment.as_str());
+    }
+
+    fn write_arithmetic(&mut self, op: &str) {
+        self.previous_label = None;
+        let code = match op {
+            "add" => "POP %cx\nPOP %ax\nadd %cx, %ax",
+            "sub" => "POP %cx\nPOP %ax\nsub %cx, %ax",
+            "and" => "POP %cx\nPOP %ax\nand %cx, %ax",
+            "or" => "POP %cx\nPOP %ax\nor %cx, %ax",
+            "neg" => "POP %ax\nneg %ax",
+            "not" => "POP %ax\nnot %ax",
+            // Like on Hack, comparisons look at the wrapped difference
+            "eq" => "POP %cx\nPOP %ax\nsub %cx, %ax\nsete %al\nBOOL",
+            "gt" => "POP %cx\nPOP %ax\nsub %cx, %ax\ntest %ax, %ax\nsetg %al\nBOOL",
+            "lt" => "POP %cx\nPOP %ax\nsub %cx, %ax\ntest %ax, %ax\nsetl %al\nBOOL",
+            "mul" => "POP %cx\nPOP %ax\nimul %cx, %ax",
+            "div" => "POP %cx\nPOP %ax\nDIVIDE",
+            "mod" => "POP %cx\nPOP %ax\nDIVIDE\nmov %edx, %eax",
+            "shl" => "POP %cx\nPOP %ax\nSHIFT shl",
+            "shr" => "POP %cx\nPOP %ax\nSHIFT shr",
+            _ => panic!("Tried to do math on a not math ({:?}) thing!", op),
+        };
+        self.push_line(code);
+        self.push_line("PUSH %ax");
+    }
+
+    fn write_push_pop(&mut self, command_type: CommandType, segment: &str, index: i16) {
+        self.previous_label = None;
+        let code = match command_type {
+            CommandType::PUSH if segment == "constant" => format!("PUSH ${index}"),
+            CommandType::PUSH => {
+                let (address, operand) = self.segment_operand(segment, index);
+                format!("{address}movw {operand}, %ax\nPUSH %ax")
+            }
+            CommandType::POP if segment == "constant" => panic!("Can't pop constant!"),
+            CommandType::POP if segment == "pointer" => match index {
+                0 => String::from("POP %ax\nmovswq %ax, %r15"),
+                1 => String::from("POP %ax\nmovswq %ax, %rbx"),
+                _ => panic!("pointer can only be 0 or 1!"),
+            },
+            CommandType::POP => {
+                let (address, operand) = self.segment_operand(segment, index);
+                format!("POP %ax\n{address}movw %ax, {operand}")
+            }
+            _ => panic!("Error in matching what command to run in push_pop!"),
+        };
+        self.push_line(code.as_str());
+    }
+
+    fn write_label(&mut self, label: &str) {
+        let line = format!("{}:", self.scoped_label(label));
+        self.push_line(line.as_str());
+        self.previous_label = Some(String::from(label));
+    }
+
+    fn write_goto(&mut self, label: &str) {
+        // `label X; goto X` spins forever, which is how Hack programs halt
+        let line = if self.previous_label.as_deref() == Some(label) {
+            String::from("jmp halt")
+        } else {
+            format!("jmp {}", self.scoped_label(label))
+        };
+        self.push_line(line.as_str());
+        self.previous_label = None;
+    }
+
+    fn write_if(&mut self, label: &str) {
+        self.previous_label = None;
+        let code = format!("POP %ax\ntest %ax, %ax\njnz {}", self.scoped_label(label));
+        self.push_line(code.as_str());
+    }
+
+    fn write_function(&mut self, function_name: &str, n_vars: i16) {
+        self.previous_label = None;
+        let line = format!("{}:", c_label("F", function_name));
+        self.push_line(line.as_str());
+        for _ in 0..n_vars {
+            self.push_line("PUSH $0");
+        }
+        self.current_function = String::from(function_name.split('.').nth(1).unwrap());
+    }
+
+    fn write_call(&mut self, function_name: &str, n_args: i16) {
+        self.previous_label = None;
+        let return_id = self.call_counter;
+        self.call_counter += 1;
+        let call = formatdoc! {"
+            PUSH ${return_id}
+            PUSH %r13w
+            PUSH %r14w
+            PUSH %r15w
+            PUSH %bx
+            lea -{}(%r12), %r14
+            mov %r12, %r13
+            jmp {}
+            R{return_id}:",
+            5 + n_args,
+            c_label("F", function_name)
+        };
+        self.push_line(call.as_str());
+    }
+
+    fn write_return(&mut self) {
+        self.previous_label = None;
+        self.push_line("jmp vm_return");
+    }
+
+    fn write_output(&self, out_path: &str) {
+        let mut return_table = String::new();
+        for return_id in 0..self.call_counter {
+            return_table.push_str(format!("    .quad R{return_id}\n").as_str());
+        }
+        let program = formatdoc! {r#"
+            # Generated by vm-translator-rust. Build with:
+            #   as -o program.o program.s && ld -o program program.o
+            #
+            # The program exits with the low byte of RAM[256] as its status. Run it
+            # with any argument to also write RAM to stdout once it halts, as
+            # little-endian 16-bit words.
+
+            # %rbp = RAM, %r12 = SP, %r13 = LCL, %r14 = ARG, %r15 = THIS, %rbx = THAT
+
+            # Point %rsi at RAM[base + offset], wrapped to 15 bits like ADDR in C
+            .macro ADDR base, offset
+                lea \offset(\base), %rsi
+                and $0x7FFF, %esi
+            .endm
+
+            .macro PUSH value
+                movw \value, (%rbp,%r12,2)
+                inc %r12d
+                and $0x7FFF, %r12d
+            .endm
+
+            .macro POP reg
+                dec %r12d
+                and $0x7FFF, %r12d
+                movw (%rbp,%r12,2), \reg
+            .endm
+
+            # Turn the flag in %al into Hack's true (-1) or false (0) in %ax
+            .macro BOOL
+                movzbl %al, %eax
+                neg %eax
+            .endm
+
+            # Divide %ax by %cx, leaving the quotient in %eax and remainder in %edx.
+            # Dividing by zero gives 0, leaving %ax as the remainder. Dividing in
+            # 32 bits means -32768 / -1 wraps instead of trapping.
+            .macro DIVIDE
+                movswl %ax, %eax
+                movswl %cx, %ecx
+                mov %eax, %edx
+                test %ecx, %ecx
+                jz 1f
+                cltd
+                idivl %ecx
+                jmp 2f
+            1:
+                xor %eax, %eax
+            2:
+            .endm
+
+            # Shift %ax by %cx, giving 0 for anything outside 0..=15
+            .macro SHIFT op
+                movzwl %ax, %eax
+                movzwl %cx, %ecx
+                cmp $15, %ecx
+                jbe 1f
+                xor %eax, %eax
+            1:
+                \op %cl, %eax
+            .endm
+
+                .bss
+                .align 16
+            ram:
+                .zero 65536
+            argc:
+                .zero 8
+
+                .section .rodata
+                .align 8
+            return_table:
+            {}
+                .text
+                .globl _start
+            _start:
+                mov (%rsp), %rax
+                mov %rax, argc(%rip)
+                lea ram(%rip), %rbp
+                xor %r12d, %r12d
+                xor %r13d, %r13d
+                xor %r14d, %r14d
+                xor %r15d, %r15d
+                xor %ebx, %ebx
+            {}
+            # Jump back to the return address at *(LCL-5)
+            vm_return:
+                mov %r13, %rcx
+                ADDR %rcx, -5
+                movzwl (%rbp,%rsi,2), %edx
+                POP %ax
+                ADDR %r14, 0
+                movw %ax, (%rbp,%rsi,2)
+                lea 1(%r14), %r12
+                and $0x7FFF, %r12d
+                ADDR %rcx, -1
+                movswq (%rbp,%rsi,2), %rbx
+                ADDR %rcx, -2
+                movswq (%rbp,%rsi,2), %r15
+                ADDR %rcx, -3
+                movswq (%rbp,%rsi,2), %r14
+                ADDR %rcx, -4
+                movswq (%rbp,%rsi,2), %r13
+                cmp ${}, %edx
+                jae halt
+                lea return_table(%rip), %rax
+                jmp *(%rax,%rdx,8)
+
+            halt:
+                movw %r12w, 0(%rbp)
+                movw %r13w, 2(%rbp)
+                movw %r14w, 4(%rbp)
+                movw %r15w, 6(%rbp)
+                movw %bx, 8(%rbp)
+                cmpq $1, argc(%rip)
+                jbe exit
+                # write(1, ram, 65536)
+                mov $1, %eax
+                mov $1, %edi
+                mov %rbp, %rsi
+                mov $65536, %edx
+                syscall
+            exit:
+                # exit(RAM[256])
+                movzbl 512(%rbp), %edi
+                mov $60, %eax
+                syscall
+            "#,
+            return_table, self.code, self.call_counter
+        };
+        let mut out_file =
+            File::create(format!("{out_path}.s")).expect("Unable to create new file");
+        out_file
+            .write_all(program.as_bytes())
+            .expect("Error writing to file");
+    }
+}